@@ -0,0 +1,3 @@
+ALTER TABLE email_results
+DROP COLUMN external_id,
+DROP COLUMN meta;
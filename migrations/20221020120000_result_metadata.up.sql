@@ -0,0 +1,3 @@
+ALTER TABLE email_results
+ADD external_id TEXT,
+ADD meta JSONB;
@@ -0,0 +1,5 @@
+DROP INDEX email_results_job_id_row_index;
+
+ALTER TABLE email_results
+DROP COLUMN row_index,
+DROP COLUMN raw_input;
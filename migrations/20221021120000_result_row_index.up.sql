@@ -0,0 +1,5 @@
+ALTER TABLE email_results
+ADD row_index INTEGER,
+ADD raw_input TEXT;
+
+CREATE INDEX email_results_job_id_row_index ON email_results (job_id, row_index);
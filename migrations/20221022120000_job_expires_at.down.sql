@@ -0,0 +1,6 @@
+DROP INDEX mq_payloads_job_id;
+DROP INDEX bulk_jobs_expires_at;
+
+ALTER TABLE bulk_jobs
+DROP COLUMN expires_at,
+DROP COLUMN anonymized_at;
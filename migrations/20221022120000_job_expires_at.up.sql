@@ -0,0 +1,8 @@
+ALTER TABLE bulk_jobs
+ADD expires_at TIMESTAMPTZ,
+ADD anonymized_at TIMESTAMPTZ;
+
+CREATE INDEX bulk_jobs_expires_at ON bulk_jobs (expires_at);
+
+-- Tasks of a job, looked up by the janitor.
+CREATE INDEX mq_payloads_job_id ON mq_payloads (((payload_json ->> 'id')::INTEGER));
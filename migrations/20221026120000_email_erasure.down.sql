@@ -0,0 +1,7 @@
+DROP TABLE email_erasures;
+
+DROP INDEX mq_payloads_to_email;
+
+DROP INDEX email_results_normalized_email;
+
+ALTER TABLE email_results DROP COLUMN normalized_email;
@@ -0,0 +1,20 @@
+ALTER TABLE email_results ADD normalized_email TEXT;
+
+UPDATE email_results SET normalized_email = LOWER(TRIM(result ->> 'input'));
+
+CREATE INDEX email_results_normalized_email ON email_results (normalized_email);
+
+CREATE TABLE email_erasures (
+    id SERIAL PRIMARY KEY,
+    -- Salted hash of the address, NULL without a salt.
+    email_hash TEXT,
+    mode TEXT NOT NULL,
+    results_count INTEGER NOT NULL,
+    tasks_count INTEGER NOT NULL,
+    erased_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX email_erasures_email_hash ON email_erasures (email_hash);
+
+-- Tasks not run yet, looked up by email.
+CREATE INDEX mq_payloads_to_email ON mq_payloads (LOWER(TRIM(payload_json -> 'input' -> 'to_emails' ->> 0)));
@@ -0,0 +1,441 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Verify a file of emails locally, without the HTTP server nor Postgres.
+//!
+//! The emails are verified like the tasks of a bulk job, and the results are
+//! written in the same format as `GET /v0/bulk/{id}/results`. The row index
+//! of each written result is appended to a checkpoint file, so that an
+//! interrupted run can be resumed. Results are written as they come, so not
+//! in the order of the input.
+
+use crate::config::Config;
+use crate::routes::bulk::{
+	check_task_input,
+	results::{CsvWrapper, JobResultCsvResponse},
+	task_result, SmtpPort, TaskInput,
+};
+use crate::state::AppState;
+use futures::stream::{self, StreamExt};
+use serde_json::{Map, Value};
+use std::{
+	collections::HashSet,
+	convert::TryInto,
+	error::Error,
+	fs::{self, File, OpenOptions},
+	io::{BufRead, BufReader, Write},
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Format of the input file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputFormat {
+	/// CSV with a header row. The emails are in the `email` column, an
+	/// optional `external_id` column is kept as is, and all the other
+	/// columns are stored in `meta`.
+	Csv,
+	/// One email per line. Blank lines are ignored.
+	Lines,
+}
+
+impl InputFormat {
+	/// Deduce the format from the file extension.
+	pub fn from_path(path: &Path) -> Self {
+		match path.extension().and_then(|e| e.to_str()) {
+			Some(ext) if ext.eq_ignore_ascii_case("csv") => InputFormat::Csv,
+			_ => InputFormat::Lines,
+		}
+	}
+}
+
+/// Format of the output file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+	/// Same columns as the CSV results of a bulk job.
+	Csv,
+	/// One JSON result per line.
+	Ndjson,
+}
+
+/// Options of a bulk file verification.
+#[derive(Debug)]
+pub struct BulkFileOptions {
+	pub input: PathBuf,
+	pub input_format: InputFormat,
+	pub output: PathBuf,
+	pub output_format: OutputFormat,
+	/// Defaults to the output path with a `.checkpoint` suffix.
+	pub checkpoint: Option<PathBuf>,
+	/// Continue a previous run, skipping the rows found in the checkpoint.
+	pub resume: bool,
+	/// Number of emails verified at the same time.
+	pub concurrency: usize,
+	pub smtp_ports: Vec<u16>,
+}
+
+/// Outcome of a bulk file verification.
+#[derive(Debug, Default)]
+pub struct BulkFileSummary {
+	/// Number of results written by this run.
+	pub verified: usize,
+	/// Number of rows already verified by a previous run.
+	pub skipped: usize,
+	/// Number of rows without a result, e.g. without any SMTP port to verify
+	/// them on. They're left out of the checkpoint, to be retried with
+	/// `--resume`.
+	pub failed: usize,
+}
+
+/// One email of the input file.
+#[derive(Debug, PartialEq)]
+struct InputRow {
+	row_index: i32,
+	raw_input: String,
+	external_id: Option<String>,
+	meta: Option<Value>,
+}
+
+/// Read the emails of the input file.
+fn read_input(path: &Path, format: InputFormat) -> Result<Vec<InputRow>, BoxError> {
+	let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+
+	match format {
+		InputFormat::Lines => {
+			let mut rows = vec![];
+			for line in BufReader::new(file).lines() {
+				let line = line?;
+				if !line.trim().is_empty() {
+					rows.push(InputRow {
+						row_index: rows.len() as i32,
+						raw_input: line,
+						external_id: None,
+						meta: None,
+					});
+				}
+			}
+
+			Ok(rows)
+		}
+		InputFormat::Csv => {
+			let mut reader = csv::Reader::from_reader(file);
+			let headers = reader.headers()?.clone();
+			let email_column = headers
+				.iter()
+				.position(|h| h.trim().eq_ignore_ascii_case("email"))
+				.ok_or_else(|| format!("{} has no `email` column", path.display()))?;
+
+			let mut rows = vec![];
+			for record in reader.records() {
+				let record = record?;
+				let mut external_id = None;
+				let mut meta = Map::new();
+				for (i, (header, value)) in headers.iter().zip(record.iter()).enumerate() {
+					if i == email_column {
+						continue;
+					} else if header.trim() == "external_id" {
+						external_id = Some(value.to_string());
+					} else {
+						meta.insert(header.to_string(), value.into());
+					}
+				}
+
+				rows.push(InputRow {
+					row_index: rows.len() as i32,
+					raw_input: record.get(email_column).unwrap_or_default().to_string(),
+					external_id,
+					meta: Some(Value::Object(meta)).filter(|m| m != &Value::Object(Map::new())),
+				});
+			}
+
+			Ok(rows)
+		}
+	}
+}
+
+/// Read the row indexes of the results written by a previous run.
+fn read_checkpoint(path: &Path) -> Result<HashSet<i32>, BoxError> {
+	if !path.exists() {
+		return Ok(HashSet::new());
+	}
+
+	let mut done = HashSet::new();
+	for line in BufReader::new(File::open(path)?).lines() {
+		let line = line?;
+		if !line.trim().is_empty() {
+			done.insert(
+				line.trim()
+					.parse()
+					.map_err(|_| format!("Invalid line in {}: {}", path.display(), line))?,
+			);
+		}
+	}
+
+	Ok(done)
+}
+
+/// Verify all the emails of the input file, and write their results.
+pub async fn verify_file(
+	config: &Config,
+	state: &AppState,
+	opts: BulkFileOptions,
+) -> Result<BulkFileSummary, BoxError> {
+	let checkpoint = opts.checkpoint.clone().unwrap_or_else(|| {
+		let mut path = opts.output.clone().into_os_string();
+		path.push(".checkpoint");
+		path.into()
+	});
+	if !opts.resume && checkpoint.exists() {
+		return Err(format!(
+			"{} exists, pass --resume to continue the previous run, or delete it",
+			checkpoint.display()
+		)
+		.into());
+	}
+	// Every row would be appended again to the results of the previous run.
+	if opts.resume && !checkpoint.exists() && opts.output.exists() {
+		return Err(format!(
+			"{} doesn't exist, so {} can't be resumed, delete it to start over",
+			checkpoint.display(),
+			opts.output.display()
+		)
+		.into());
+	}
+
+	let rows = read_input(&opts.input, opts.input_format)?;
+	let done = if opts.resume {
+		read_checkpoint(&checkpoint)?
+	} else {
+		HashSet::new()
+	};
+	let todo: Vec<InputRow> = rows
+		.into_iter()
+		.filter(|row| !done.contains(&row.row_index))
+		.collect();
+	let mut summary = BulkFileSummary {
+		skipped: done.len(),
+		..Default::default()
+	};
+	log::info!(
+		target: "reacher",
+		"Verifying [count={}] emails from [input={}], [skipped={}] already in checkpoint.",
+		todo.len(),
+		opts.input.display(),
+		summary.skipped,
+	);
+
+	let output = OpenOptions::new()
+		.create(true)
+		.write(true)
+		.append(opts.resume)
+		.truncate(!opts.resume)
+		.open(&opts.output)?;
+	// When resuming, the header is already there.
+	let mut csv_writer = csv::WriterBuilder::new()
+		.has_headers(output.metadata()?.len() == 0)
+		.from_writer(output.try_clone()?);
+	let mut output = output;
+	let mut checkpoint_file = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(&checkpoint)?;
+
+	let throttle = Arc::new(config.bulk.domain_throttle());
+	let template = TaskInput {
+		to_emails: vec![],
+		smtp_ports: opts.smtp_ports.iter().map(|p| SmtpPort::Port(*p)).collect(),
+		smtp_port_fallback: config.bulk.smtp_port_fallback,
+		proxy: None,
+		hello_name: None,
+		from_email: Some(config.from_email.clone()),
+		smtp_timeout: Some(config.smtp.smtp_timeout(None)),
+		retries: Some(config.smtp.retries(None)),
+		deadline: config.smtp.deadline(None),
+		external_id: None,
+		meta: None,
+	};
+
+	let mut results = stream::iter(todo)
+		.map(|row| {
+			let input = TaskInput {
+				to_emails: vec![row.raw_input.trim().to_string()],
+				external_id: row.external_id.clone(),
+				meta: row.meta.clone(),
+				..template.clone()
+			};
+			let (throttle, limits) = (throttle.clone(), &config.smtp);
+			async move { (row, check_task_input(input, &throttle, limits, state).await) }
+		})
+		.buffer_unordered(opts.concurrency.max(1));
+
+	while let Some((row, response)) = results.next().await {
+		let task_output = match response {
+			Some(task_output) => task_output,
+			// No SMTP port to verify the email on.
+			None => {
+				log::warn!(
+					target: "reacher",
+					"No result for [row_index={}], it's left out of the checkpoint.",
+					row.row_index
+				);
+				summary.failed += 1;
+				continue;
+			}
+		};
+
+		let mut result = task_result(&task_output, &state.classifier);
+		if let Some(external_id) = row.external_id {
+			result["external_id"] = external_id.into();
+		}
+		if let Some(meta) = row.meta {
+			result["meta"] = meta;
+		}
+		result["row_index"] = row.row_index.into();
+		result["raw_input"] = row.raw_input.into();
+
+		match opts.output_format {
+			OutputFormat::Csv => {
+				let record: JobResultCsvResponse = CsvWrapper(result).try_into()?;
+				csv_writer.serialize(record)?;
+				csv_writer.flush()?;
+			}
+			OutputFormat::Ndjson => {
+				writeln!(output, "{}", result)?;
+				output.flush()?;
+			}
+		}
+		// Only once the result is written, so that a row is never lost. It
+		// may be written twice if the process stops in between.
+		writeln!(checkpoint_file, "{}", row.row_index)?;
+		checkpoint_file.flush()?;
+
+		summary.verified += 1;
+		if summary.verified.is_multiple_of(100) {
+			log::info!(target: "reacher", "Verified [count={}] emails.", summary.verified);
+		}
+	}
+
+	log::info!(
+		target: "reacher",
+		"Wrote [count={}] results to [output={}].",
+		summary.verified,
+		opts.output.display(),
+	);
+	// The checkpoint is kept to retry the failed rows.
+	if summary.failed == 0 {
+		fs::remove_file(&checkpoint)?;
+	}
+
+	Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	/// Directory of its own for each test, so that concurrent test runs
+	/// don't overwrite each other's files. It's removed when dropped.
+	struct TmpDir(PathBuf);
+
+	impl TmpDir {
+		fn new() -> Self {
+			static DIRS: AtomicUsize = AtomicUsize::new(0);
+			let dir = std::env::temp_dir().join(format!(
+				"reacher_bulk_file_{}_{}",
+				std::process::id(),
+				DIRS.fetch_add(1, Ordering::Relaxed)
+			));
+			fs::create_dir_all(&dir).unwrap();
+			TmpDir(dir)
+		}
+
+		/// Write `content` to the file `name` of the directory.
+		fn write(&self, name: &str, content: &str) -> PathBuf {
+			let path = self.0.join(name);
+			fs::write(&path, content).unwrap();
+			path
+		}
+	}
+
+	impl Drop for TmpDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	#[test]
+	fn test_read_input() {
+		let dir = TmpDir::new();
+		let lines = dir.write("input.txt", "a@example.com\n\n b@example.com\n");
+		let rows = read_input(&lines, InputFormat::from_path(&lines)).unwrap();
+		assert_eq!(rows.len(), 2);
+		assert_eq!(rows[1].row_index, 1);
+		assert_eq!(rows[1].raw_input, " b@example.com");
+
+		let csv = dir.write(
+			"input.csv",
+			"name,Email,external_id\nAlice,a@example.com,42\n",
+		);
+		let rows = read_input(&csv, InputFormat::from_path(&csv)).unwrap();
+		assert_eq!(
+			rows,
+			vec![InputRow {
+				row_index: 0,
+				raw_input: "a@example.com".into(),
+				external_id: Some("42".into()),
+				meta: Some(serde_json::json!({ "name": "Alice" })),
+			}]
+		);
+
+		let no_email = dir.write("no_email.csv", "name\nAlice\n");
+		assert!(read_input(&no_email, InputFormat::Csv).is_err());
+	}
+
+	#[test]
+	fn test_read_checkpoint() {
+		let dir = TmpDir::new();
+		let path = dir.write("test.checkpoint", "0\n2\n");
+		assert_eq!(read_checkpoint(&path).unwrap(), HashSet::from([0, 2]));
+		fs::remove_file(&path).unwrap();
+		assert!(read_checkpoint(&path).unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_resume_without_checkpoint() {
+		let dir = TmpDir::new();
+		let output = dir.write("resumed.csv", "email\n");
+		let opts = BulkFileOptions {
+			input: dir.write("resumed.txt", "a@example.com\n"),
+			input_format: InputFormat::Lines,
+			output: output.clone(),
+			output_format: OutputFormat::Csv,
+			checkpoint: None,
+			resume: true,
+			concurrency: 1,
+			smtp_ports: vec![25],
+		};
+
+		let config = Config::default();
+		let err = verify_file(&config, &AppState::new(&config), opts)
+			.await
+			.unwrap_err();
+		assert!(err.to_string().contains("can't be resumed"));
+		assert_eq!(fs::read_to_string(&output).unwrap(), "email\n");
+	}
+}
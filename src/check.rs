@@ -16,21 +16,94 @@
 
 //! This file contains shared logic for checking one email.
 
-use super::sentry_util;
-use check_if_email_exists::{check_email as ciee_check_email, CheckEmailInput, CheckEmailOutput};
-use std::time::Instant;
+use super::{metrics, sentry_util};
+use crate::state::AppState;
+use async_smtp::smtp::error::Error as AsyncSmtpError;
+use check_if_email_exists::{
+	check_email as ciee_check_email, smtp::SmtpError, syntax::check_syntax, CheckEmailInput,
+	CheckEmailOutput, Reachable,
+};
+use serde::{Deserialize, Serialize};
+use std::{time::Duration, time::Instant};
 
-/// Timeout after which we drop the `check-if-email-exists` check. We run the
-/// checks twice (to avoid greylisting), so each verification takes 20s max.
+/// Default timeout after which we drop the `check-if-email-exists` check. We
+/// run the checks twice by default (to avoid greylisting), so each
+/// verification takes 20s max.
 pub const SMTP_TIMEOUT: u64 = 10;
 
+/// Default number of SMTP connection retries, same as `check-if-email-exists`.
+pub const SMTP_RETRIES: usize = 2;
+
+/// Server-side maxima for the SMTP options that each request can override.
+/// It's the `[smtp]` section of the [`Config`](crate::config::Config).
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpLimits {
+	/// Maximum SMTP timeout, in seconds, `RCH_MAX_SMTP_TIMEOUT_SECS`.
+	#[serde(rename = "max_timeout_secs")]
+	pub max_smtp_timeout: u64,
+	/// Maximum number of SMTP connection retries, `RCH_MAX_SMTP_RETRIES`.
+	pub max_retries: usize,
+	/// Maximum duration of a whole verification, in seconds. If set, it also
+	/// applies to requests which don't specify a deadline.
+	/// `RCH_MAX_DEADLINE_SECS`.
+	#[serde(rename = "max_deadline_secs")]
+	pub max_deadline: Option<u64>,
+}
+
+impl Default for SmtpLimits {
+	fn default() -> Self {
+		SmtpLimits {
+			max_smtp_timeout: 60,
+			max_retries: 5,
+			max_deadline: None,
+		}
+	}
+}
+
+impl SmtpLimits {
+	/// SMTP timeout in seconds, defaulting to [`SMTP_TIMEOUT`].
+	pub fn smtp_timeout(&self, requested: Option<u64>) -> u64 {
+		requested
+			.unwrap_or(SMTP_TIMEOUT)
+			.clamp(1, self.max_smtp_timeout.max(1))
+	}
+
+	/// Number of SMTP retries, defaulting to [`SMTP_RETRIES`].
+	pub fn retries(&self, requested: Option<usize>) -> usize {
+		requested.unwrap_or(SMTP_RETRIES).min(self.max_retries)
+	}
+
+	/// Deadline of the whole verification in seconds, if any.
+	pub fn deadline(&self, requested: Option<u64>) -> Option<u64> {
+		match (requested, self.max_deadline) {
+			(Some(requested), Some(max)) => Some(requested.min(max)),
+			(requested, max) => requested.or(max),
+		}
+	}
+}
+
+/// Normalized form of an email, under which its results are stored, to be
+/// found by `DELETE /v0/emails/{email}`.
+pub fn normalize_email(email: &str) -> String {
+	email.trim().to_lowercase()
+}
+
 /// Same as `check-if-email-exists`'s check email, but adds some additional
 /// logging and error handling, and also only handles 1 email.
 ///
 /// # Panics
 ///
 /// If more than 1 email is passed inside input, then this function panics.
-pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
+#[tracing::instrument(
+	skip_all,
+	fields(
+		domain = input.to_emails[0].rsplit_once('@').map(|(_, d)| d).unwrap_or_default(),
+		smtp_port = input.smtp_port,
+		is_reachable,
+	)
+)]
+pub async fn check_email(input: &CheckEmailInput, state: &AppState) -> CheckEmailOutput {
 	// Run `ciee_check_email` with retries if necessary. Also measure the
 	// verification time.
 	let now = Instant::now();
@@ -45,16 +118,78 @@ pub async fn check_email(input: &CheckEmailInput) -> CheckEmailOutput {
 		.pop()
 		.expect("Input only has one email, so does output. qed.");
 
-	// Log on Sentry the `is_reachable` field.
-	// We should definitely log this somewhere else than Sentry.
-	// TODO https://github.com/reacherhq/backend/issues/207
+	tracing::Span::current().record("is_reachable", format!("{:?}", res.is_reachable).as_str());
+	let elapsed = now.elapsed();
+	let category = state.classifier.classify(&res);
+	metrics::observe_verification(&res, category, elapsed);
+	// Legacy analytics, only sent if enabled with `RCH_SENTRY_METRICS`.
 	sentry_util::metrics(
 		format!("is_reachable={:?}", res.is_reachable),
-		now.elapsed().as_millis(),
+		elapsed.as_millis(),
 		res.syntax.domain.as_ref(),
 	);
 
-	sentry_util::log_unknown_errors(&res);
+	sentry_util::log_unknown_errors(&res, category, &state.redactor);
 
 	res
 }
+
+/// Same as [`check_email`], but gives up after `timeout`. In this case, an
+/// `unknown` result is returned, with a "deadline exceeded" SMTP error.
+pub async fn check_email_with_timeout(
+	input: &CheckEmailInput,
+	timeout: Option<Duration>,
+	state: &AppState,
+) -> CheckEmailOutput {
+	match timeout {
+		Some(timeout) => tokio::time::timeout(timeout, check_email(input, state))
+			.await
+			.unwrap_or_else(|_| deadline_exceeded(&input.to_emails[0])),
+		None => check_email(input, state).await,
+	}
+}
+
+/// Output of a verification which didn't finish before its deadline.
+fn deadline_exceeded(to_email: &str) -> CheckEmailOutput {
+	log::debug!(target: "reacher", "Deadline exceeded for [email={}]", to_email);
+
+	CheckEmailOutput {
+		input: to_email.into(),
+		is_reachable: Reachable::Unknown,
+		smtp: Err(SmtpError::SmtpError(AsyncSmtpError::Client(
+			"Verification deadline exceeded",
+		))),
+		syntax: check_syntax(to_email),
+		..Default::default()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::SmtpLimits;
+
+	#[test]
+	fn test_smtp_limits() {
+		let limits = SmtpLimits {
+			max_smtp_timeout: 30,
+			max_retries: 3,
+			max_deadline: None,
+		};
+
+		assert_eq!(limits.smtp_timeout(None), 10);
+		assert_eq!(limits.smtp_timeout(Some(0)), 1);
+		assert_eq!(limits.smtp_timeout(Some(100)), 30);
+		assert_eq!(limits.retries(None), 2);
+		assert_eq!(limits.retries(Some(10)), 3);
+		assert_eq!(limits.deadline(None), None);
+		assert_eq!(limits.deadline(Some(100)), Some(100));
+
+		let limits = SmtpLimits {
+			max_deadline: Some(60),
+			..limits
+		};
+		assert_eq!(limits.deadline(None), Some(60));
+		assert_eq!(limits.deadline(Some(100)), Some(60));
+		assert_eq!(limits.deadline(Some(5)), Some(5));
+	}
+}
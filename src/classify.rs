@@ -0,0 +1,403 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Classification of the errors found in verification results.
+//!
+//! The first error of a result (misc, then MX, then SMTP) is mapped to a
+//! stable [`ErrorCategory`] by a list of rules: the ones from the config
+//! first, then the built-in ones. The category is returned in the
+//! `error_category` field of the results, used as a metrics label, and only
+//! `unknown` errors are reported to Sentry.
+
+use async_smtp::smtp::error::Error as AsyncSmtpError;
+use check_if_email_exists::{mx::MxError, smtp::SmtpError, CheckEmailOutput};
+use serde::{Deserialize, Serialize};
+
+/// Stable category of a verification error.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+	/// The server asked us to try again later.
+	Greylisted,
+	/// The server refused to talk to us, e.g. because our IP is blacklisted.
+	Blocked,
+	/// The server throttled us.
+	RateLimited,
+	/// The verification, or the SMTP connection, timed out.
+	Timeout,
+	ConnectionReset,
+	ConnectionRefused,
+	Tls,
+	/// Error of the SOCKS5 proxy.
+	Proxy,
+	/// Error while resolving the MX records or the SMTP host.
+	Dns,
+	/// Any other error, reported to Sentry.
+	Unknown,
+}
+
+impl ErrorCategory {
+	/// Name of the category, as serialized.
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ErrorCategory::Greylisted => "greylisted",
+			ErrorCategory::Blocked => "blocked",
+			ErrorCategory::RateLimited => "rate_limited",
+			ErrorCategory::Timeout => "timeout",
+			ErrorCategory::ConnectionReset => "connection_reset",
+			ErrorCategory::ConnectionRefused => "connection_refused",
+			ErrorCategory::Tls => "tls",
+			ErrorCategory::Proxy => "proxy",
+			ErrorCategory::Dns => "dns",
+			ErrorCategory::Unknown => "unknown",
+		}
+	}
+}
+
+/// Step of the verification which failed.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorStep {
+	Misc,
+	Mx,
+	Smtp,
+}
+
+/// Kind of the error, as returned by `check-if-email-exists`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+	/// 4xx SMTP reply.
+	Transient,
+	/// 5xx SMTP reply.
+	Permanent,
+	Io,
+	Tls,
+	Timeout,
+	Socks,
+	Dns,
+	Other,
+}
+
+/// The first error found in a verification result.
+#[derive(Debug)]
+struct VerificationError {
+	step: ErrorStep,
+	kind: ErrorKind,
+	/// The SMTP reply for 4xx and 5xx errors, the error itself otherwise.
+	message: String,
+	/// For IO errors.
+	os_error: Option<i32>,
+}
+
+impl VerificationError {
+	fn from_output(output: &CheckEmailOutput) -> Option<Self> {
+		let error = |step, kind, message: String, os_error| VerificationError {
+			step,
+			kind,
+			message,
+			os_error,
+		};
+
+		match (&output.misc, &output.mx, &output.smtp) {
+			(Err(e), _, _) => Some(error(
+				ErrorStep::Misc,
+				ErrorKind::Other,
+				format!("{:?}", e),
+				None,
+			)),
+			(_, Err(MxError::IoError(e)), _) => Some(error(
+				ErrorStep::Mx,
+				ErrorKind::Io,
+				e.to_string(),
+				e.raw_os_error(),
+			)),
+			(_, Err(MxError::ResolveError(e)), _) => {
+				Some(error(ErrorStep::Mx, ErrorKind::Dns, e.to_string(), None))
+			}
+			(_, _, Err(e)) => {
+				let (kind, message, os_error) = match e {
+					SmtpError::SmtpError(AsyncSmtpError::Transient(r)) => {
+						(ErrorKind::Transient, r.message.join(" "), None)
+					}
+					SmtpError::SmtpError(AsyncSmtpError::Permanent(r)) => {
+						(ErrorKind::Permanent, r.message.join(" "), None)
+					}
+					SmtpError::SmtpError(AsyncSmtpError::Io(e)) => {
+						(ErrorKind::Io, e.to_string(), e.raw_os_error())
+					}
+					SmtpError::SmtpError(AsyncSmtpError::Tls(e)) => {
+						(ErrorKind::Tls, e.to_string(), None)
+					}
+					SmtpError::SmtpError(AsyncSmtpError::Timeout(e)) => {
+						(ErrorKind::Timeout, e.to_string(), None)
+					}
+					SmtpError::SmtpError(e @ AsyncSmtpError::Resolution) => {
+						(ErrorKind::Dns, e.to_string(), None)
+					}
+					SmtpError::SmtpError(e) => (ErrorKind::Other, e.to_string(), None),
+					SmtpError::TimeoutError(e) => (ErrorKind::Timeout, e.to_string(), None),
+					SmtpError::SocksError(e) => (ErrorKind::Socks, e.to_string(), None),
+					SmtpError::YahooError(e) => (ErrorKind::Other, format!("{:?}", e), None),
+				};
+				Some(error(ErrorStep::Smtp, kind, message, os_error))
+			}
+			(Ok(_), Ok(_), Ok(_)) => None,
+		}
+	}
+}
+
+/// A rule of the classifier. All the conditions which are set must match.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorRule {
+	pub category: ErrorCategory,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub step: Option<ErrorStep>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub kind: Option<ErrorKind>,
+	/// Case-insensitive substrings, one of which must be in the message.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub contains: Vec<String>,
+	/// OS error code of IO errors, e.g. 104 for "connection reset by peer".
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub os_error: Option<i32>,
+}
+
+impl ErrorRule {
+	fn new(category: ErrorCategory) -> Self {
+		ErrorRule {
+			category,
+			step: None,
+			kind: None,
+			contains: vec![],
+			os_error: None,
+		}
+	}
+
+	/// A rule without conditions would match all errors.
+	pub fn has_conditions(&self) -> bool {
+		self.step.is_some()
+			|| self.kind.is_some()
+			|| !self.contains.is_empty()
+			|| self.os_error.is_some()
+	}
+
+	fn matches(&self, error: &VerificationError) -> bool {
+		let message = error.message.to_lowercase();
+
+		self.step.is_none_or(|step| step == error.step)
+			&& self.kind.is_none_or(|kind| kind == error.kind)
+			&& self
+				.os_error
+				.is_none_or(|code| Some(code) == error.os_error)
+			&& (self.contains.is_empty()
+				|| self
+					.contains
+					.iter()
+					.any(|s| message.contains(&s.to_lowercase())))
+	}
+}
+
+/// The built-in rules, applied after the ones of the config.
+fn default_rules() -> Vec<ErrorRule> {
+	let contains = |category, words: &[&str]| ErrorRule {
+		contains: words.iter().map(|w| w.to_string()).collect(),
+		..ErrorRule::new(category)
+	};
+
+	vec![
+		ErrorRule {
+			kind: Some(ErrorKind::Transient),
+			// 4.3.2 Please try again later
+			// Temporary local problem - please try later
+			contains: vec!["greylist".into(), "try again".into(), "try later".into()],
+			..ErrorRule::new(ErrorCategory::Greylisted)
+		},
+		contains(
+			ErrorCategory::RateLimited,
+			&["too many", "rate limit", "throttl"],
+		),
+		contains(
+			ErrorCategory::Blocked,
+			&[
+				"blacklist",
+				"blocklist",
+				"spamhaus",
+				"blocked",
+				"reverse dns",
+				"rdns",
+			],
+		),
+		ErrorRule {
+			kind: Some(ErrorKind::Io),
+			os_error: Some(104),
+			..ErrorRule::new(ErrorCategory::ConnectionReset)
+		},
+		ErrorRule {
+			kind: Some(ErrorKind::Io),
+			// The server closed the connection in the middle of a reply.
+			contains: vec!["incomplete".into()],
+			..ErrorRule::new(ErrorCategory::ConnectionReset)
+		},
+		ErrorRule {
+			kind: Some(ErrorKind::Io),
+			os_error: Some(111),
+			..ErrorRule::new(ErrorCategory::ConnectionRefused)
+		},
+		ErrorRule {
+			kind: Some(ErrorKind::Timeout),
+			..ErrorRule::new(ErrorCategory::Timeout)
+		},
+		// See `crate::check::check_email_with_timeout`.
+		contains(ErrorCategory::Timeout, &["deadline exceeded"]),
+		ErrorRule {
+			kind: Some(ErrorKind::Tls),
+			..ErrorRule::new(ErrorCategory::Tls)
+		},
+		ErrorRule {
+			kind: Some(ErrorKind::Socks),
+			..ErrorRule::new(ErrorCategory::Proxy)
+		},
+		ErrorRule {
+			kind: Some(ErrorKind::Dns),
+			..ErrorRule::new(ErrorCategory::Dns)
+		},
+	]
+}
+
+/// Maps verification errors to categories.
+#[derive(Debug)]
+pub struct ErrorClassifier {
+	rules: Vec<ErrorRule>,
+}
+
+impl ErrorClassifier {
+	/// A classifier applying `rules`, then the built-in rules.
+	pub fn new(rules: &[ErrorRule]) -> Self {
+		ErrorClassifier {
+			rules: rules.iter().cloned().chain(default_rules()).collect(),
+		}
+	}
+
+	/// Category of the first error of the result, if any.
+	pub fn classify(&self, output: &CheckEmailOutput) -> Option<ErrorCategory> {
+		let error = VerificationError::from_output(output)?;
+
+		Some(
+			self.rules
+				.iter()
+				.find(|rule| rule.matches(&error))
+				.map(|rule| rule.category)
+				.unwrap_or(ErrorCategory::Unknown),
+		)
+	}
+
+	/// Serialize a verification result, adding its `error_category` if it
+	/// has an error.
+	pub fn to_json(&self, output: &CheckEmailOutput) -> serde_json::Value {
+		let mut json = serde_json::json!(output);
+		if let Some(category) = self.classify(output) {
+			json["error_category"] = category.as_str().into();
+		}
+
+		json
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_smtp::smtp::response::{Category, Code, Detail, Response, Severity};
+	use std::io;
+
+	fn smtp_error(error: SmtpError) -> CheckEmailOutput {
+		CheckEmailOutput {
+			smtp: Err(error),
+			..Default::default()
+		}
+	}
+
+	fn transient(message: &str) -> SmtpError {
+		SmtpError::SmtpError(AsyncSmtpError::Transient(Response::new(
+			Code::new(
+				Severity::TransientNegativeCompletion,
+				Category::MailSystem,
+				Detail::Zero,
+			),
+			vec![message.into()],
+		)))
+	}
+
+	#[test]
+	fn test_default_rules() {
+		let classifier = ErrorClassifier::new(&[]);
+		let classify = |error| classifier.classify(&smtp_error(error));
+
+		assert_eq!(classifier.classify(&CheckEmailOutput::default()), None);
+		assert_eq!(
+			classify(transient("4.3.2 Please try again later")),
+			Some(ErrorCategory::Greylisted)
+		);
+		assert_eq!(
+			classify(transient("Client host blocked using Spamhaus")),
+			Some(ErrorCategory::Blocked)
+		);
+		assert_eq!(
+			classify(SmtpError::SmtpError(AsyncSmtpError::Io(
+				io::Error::from_raw_os_error(104)
+			))),
+			Some(ErrorCategory::ConnectionReset)
+		);
+		assert_eq!(
+			classify(SmtpError::SmtpError(AsyncSmtpError::Client(
+				"Verification deadline exceeded"
+			))),
+			Some(ErrorCategory::Timeout)
+		);
+		assert_eq!(
+			classify(transient("Mailbox busy")),
+			Some(ErrorCategory::Unknown)
+		);
+	}
+
+	#[test]
+	fn test_custom_rules() {
+		let rules: Vec<ErrorRule> = toml::from_str::<toml::Value>(
+			r#"
+			[[rules]]
+			category = "blocked"
+			kind = "transient"
+			contains = ["MAILBOX BUSY"]
+			"#,
+		)
+		.unwrap()["rules"]
+			.clone()
+			.try_into()
+			.unwrap();
+		let classifier = ErrorClassifier::new(&rules);
+
+		assert_eq!(
+			classifier.classify(&smtp_error(transient("Mailbox busy"))),
+			Some(ErrorCategory::Blocked)
+		);
+		// The built-in rules still apply.
+		assert_eq!(
+			classifier.classify(&smtp_error(transient("try again later"))),
+			Some(ErrorCategory::Greylisted)
+		);
+	}
+}
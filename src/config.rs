@@ -0,0 +1,882 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Configuration of the backend.
+//!
+//! The configuration is read from an optional TOML file (`RCH_CONFIG`), then
+//! each option can be overridden by its environment variable, which keeps
+//! the historical `RCH_*` variables working. All options are validated at
+//! startup, so that a typo fails early with a clear message, instead of
+//! panicking in the middle of a request.
+
+use crate::check::SmtpLimits;
+use crate::classify::ErrorRule;
+use crate::encryption::{Keyring, StorageMode};
+use crate::proxy::{ProxyPoolConfig, ProxyPools, ProxySelection};
+use crate::redact::EmailRedaction;
+use crate::routes::bulk::{
+	DomainThrottle, GreylistingRetry, Janitor, JanitorMode, Retention, SmtpPortFallback,
+};
+use crate::routing::RoutingRule;
+use crate::telemetry::LogFormat;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{env, fmt, fs, io, net::IpAddr, path::Path, str::FromStr};
+
+/// Which parts of Reacher this process runs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+	/// Only the HTTP server.
+	Api,
+	/// Only the bulk task runners, polling the Postgres queue.
+	Worker,
+	/// Both, in the same process.
+	#[default]
+	All,
+}
+
+impl Role {
+	pub fn runs_api(&self) -> bool {
+		matches!(self, Role::Api | Role::All)
+	}
+
+	pub fn runs_worker(&self) -> bool {
+		matches!(self, Role::Worker | Role::All)
+	}
+}
+
+/// The whole configuration. Each field's environment variable is given in
+/// its doc comment.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	/// `RCH_ROLE`.
+	pub role: Role,
+	/// `RCH_HTTP_HOST`.
+	pub http_host: IpAddr,
+	/// `PORT`.
+	pub port: u16,
+	/// Default email used in `MAIL FROM`, `RCH_FROM_EMAIL`.
+	pub from_email: String,
+	/// `RCH_SHUTDOWN_TIMEOUT_SECS`.
+	pub shutdown_timeout_secs: u64,
+	/// `RCH_LOG_FORMAT`.
+	pub log_format: LogFormat,
+	/// `RCH_OTLP_ENDPOINT`.
+	pub otlp_endpoint: Option<String>,
+	/// Token of the admin endpoints, given as `Authorization: Bearer`.
+	/// They're disabled if unset. `RCH_ADMIN_TOKEN`.
+	pub admin_token: Option<String>,
+	/// Header identifying the caller in the audit log, e.g. set by an
+	/// authenticating gateway, `RCH_AUDIT_CALLER_HEADER`.
+	pub audit_caller_header: Option<String>,
+	/// Record the client address given by the reverse proxy in
+	/// `X-Forwarded-For` in the audit log, instead of the peer address. Only
+	/// enable it if the server can't be reached but through the proxy, as
+	/// clients can send the header themselves. `RCH_TRUST_FORWARDED_FOR`.
+	pub trust_forwarded_for: bool,
+	pub sentry: SentryConfig,
+	pub redaction: RedactionConfig,
+	pub encryption: EncryptionConfig,
+	pub proxies: ProxiesConfig,
+	pub smtp: SmtpLimits,
+	pub bulk: BulkConfig,
+	/// Rules classifying verification errors, applied before the built-in
+	/// ones. Only configurable in the file.
+	pub error_rules: Vec<ErrorRule>,
+	/// Per-domain overrides of the verification options. Only configurable
+	/// in the file.
+	pub routing: Vec<RoutingRule>,
+	/// Non-fatal problems found while loading, logged by
+	/// [`Config::log_summary`].
+	#[serde(skip)]
+	warnings: Vec<String>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			role: Role::default(),
+			http_host: [127, 0, 0, 1].into(),
+			port: 8080,
+			from_email: "user@example.org".into(),
+			shutdown_timeout_secs: 30,
+			log_format: LogFormat::Text,
+			otlp_endpoint: None,
+			admin_token: None,
+			audit_caller_header: None,
+			trust_forwarded_for: false,
+			sentry: SentryConfig::default(),
+			redaction: RedactionConfig::default(),
+			encryption: EncryptionConfig::default(),
+			proxies: ProxiesConfig::default(),
+			smtp: SmtpLimits::default(),
+			bulk: BulkConfig::default(),
+			error_rules: vec![],
+			routing: vec![],
+			warnings: vec![],
+		}
+	}
+}
+
+/// The `[sentry]` section.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SentryConfig {
+	/// `RCH_SENTRY_DSN`.
+	pub dsn: Option<String>,
+	/// Name of this backend in the Sentry events, `BACKEND_NAME` (or
+	/// `HEROKU_APP_NAME`).
+	pub backend_name: Option<String>,
+	/// Send the legacy analytics events, `RCH_SENTRY_METRICS`.
+	pub metrics: bool,
+}
+
+/// The `[redaction]` section, for the personal data in logs and Sentry
+/// events.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactionConfig {
+	/// `RCH_REDACT_EMAILS`.
+	pub emails: EmailRedaction,
+	/// Salt of the hashed emails, `RCH_REDACTION_SALT`.
+	pub salt: Option<String>,
+	/// Remove the proxy usernames and passwords,
+	/// `RCH_REDACT_PROXY_CREDENTIALS`.
+	pub proxy_credentials: bool,
+}
+
+impl Default for RedactionConfig {
+	fn default() -> Self {
+		RedactionConfig {
+			emails: EmailRedaction::default(),
+			salt: None,
+			proxy_credentials: true,
+		}
+	}
+}
+
+/// The `[encryption]` section, for the emails stored in the database.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+	/// `RCH_ENCRYPTION_MODE`.
+	pub mode: StorageMode,
+	/// Keys as `id:base64key`, separated by commas, the first one being used
+	/// for new results, `RCH_ENCRYPTION_KEYS`.
+	pub keys: String,
+}
+
+/// The `[proxies]` section, pools of SOCKS5 proxies used by the
+/// verifications which don't set their own proxy.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxiesConfig {
+	/// Pool used by default, `RCH_PROXY_DEFAULT_POOL`.
+	pub default_pool: Option<String>,
+	/// SOCKS errors in a row after which a proxy is ejected,
+	/// `RCH_PROXY_MAX_ERRORS`.
+	pub max_errors: u32,
+	/// Time after which an ejected proxy is used again, unless a health
+	/// check readmits it before, `RCH_PROXY_EJECTION_SECS`.
+	pub ejection_secs: u64,
+	/// 0 disables the health checks, `RCH_PROXY_HEALTH_CHECK_INTERVAL_SECS`.
+	pub health_check_interval_secs: u64,
+	/// `host:port` the health checks connect to through the proxies, e.g. a
+	/// server of our own, `RCH_PROXY_HEALTH_CHECK_TARGET`. Health checks are
+	/// disabled without it.
+	pub health_check_target: Option<String>,
+	/// Only configurable in the file, except the `default` pool, whose
+	/// proxies can be given in `RCH_PROXY_URLS`.
+	pub pools: Vec<ProxyPoolConfig>,
+}
+
+impl Default for ProxiesConfig {
+	fn default() -> Self {
+		ProxiesConfig {
+			default_pool: None,
+			max_errors: 3,
+			ejection_secs: 300,
+			health_check_interval_secs: 60,
+			health_check_target: None,
+			pools: vec![],
+		}
+	}
+}
+
+/// The `[bulk]` section.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BulkConfig {
+	/// `RCH_ENABLE_BULK`.
+	pub enabled: bool,
+	/// `DATABASE_URL`.
+	pub database_url: Option<String>,
+	/// `RCH_DATABASE_MAX_CONNECTIONS`.
+	pub database_max_connections: u32,
+	/// Apply the pending migrations on startup, `RCH_AUTO_MIGRATE`. If
+	/// disabled, run `reacher_backend migrate up` before upgrading.
+	pub auto_migrate: bool,
+	/// `RCH_MINIMUM_TASK_CONCURRENCY`.
+	pub minimum_task_concurrency: usize,
+	/// `RCH_MAXIMUM_CONCURRENT_TASK_FETCH`.
+	pub maximum_concurrent_task_fetch: usize,
+	/// `RCH_HIGH_PRIORITY_TASK_CONCURRENCY`.
+	pub high_priority_task_concurrency: usize,
+	/// `RCH_SMTP_PORT_FALLBACK`.
+	pub smtp_port_fallback: SmtpPortFallback,
+	/// `RCH_READY_MAX_QUEUE_DEPTH`.
+	pub ready_max_queue_depth: Option<i64>,
+	/// `RCH_BULK_RETENTION_DAYS`.
+	pub retention_days: Option<u32>,
+	/// `RCH_BULK_JANITOR_MODE`.
+	pub janitor_mode: JanitorMode,
+	/// `RCH_BULK_JANITOR_INTERVAL_SECS`.
+	pub janitor_interval_secs: u64,
+	/// `RCH_GREYLISTING_MAX_ATTEMPTS`.
+	pub greylisting_max_attempts: u32,
+	/// `RCH_GREYLISTING_RETRY_DELAY_SECS`.
+	pub greylisting_retry_delay_secs: u64,
+	/// `RCH_DOMAIN_CONCURRENCY`.
+	pub domain_concurrency: Option<usize>,
+	/// `RCH_DOMAIN_MIN_INTERVAL_MS`.
+	pub domain_min_interval_ms: u64,
+	/// Per-provider throttle rules, `RCH_DOMAIN_THROTTLE`. See
+	/// [`DomainThrottle::from_config`] for the format.
+	pub domain_throttle: String,
+}
+
+impl Default for BulkConfig {
+	fn default() -> Self {
+		BulkConfig {
+			enabled: false,
+			database_url: None,
+			database_max_connections: 5,
+			auto_migrate: true,
+			minimum_task_concurrency: 10,
+			maximum_concurrent_task_fetch: 20,
+			high_priority_task_concurrency: 5,
+			smtp_port_fallback: SmtpPortFallback::default(),
+			ready_max_queue_depth: None,
+			retention_days: None,
+			janitor_mode: JanitorMode::default(),
+			janitor_interval_secs: 3600,
+			greylisting_max_attempts: 3,
+			greylisting_retry_delay_secs: 60,
+			domain_concurrency: None,
+			domain_min_interval_ms: 0,
+			domain_throttle: String::new(),
+		}
+	}
+}
+
+impl BulkConfig {
+	pub fn retention(&self) -> Retention {
+		Retention::new(self.retention_days)
+	}
+
+	pub fn janitor(&self) -> Janitor {
+		Janitor::new(self.janitor_mode, self.janitor_interval_secs)
+	}
+
+	pub fn greylisting_retry(&self) -> GreylistingRetry {
+		GreylistingRetry::new(
+			self.greylisting_max_attempts,
+			self.greylisting_retry_delay_secs,
+		)
+	}
+
+	/// The concurrency and the throttle rules are checked by
+	/// [`Config::validate`].
+	pub fn domain_throttle(&self) -> DomainThrottle {
+		DomainThrottle::from_config(
+			self.domain_concurrency,
+			self.domain_min_interval_ms,
+			&self.domain_throttle,
+		)
+		.expect("Throttle settings are validated on load. qed.")
+	}
+}
+
+/// Error while loading the configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+	Read(String, io::Error),
+	Parse(String, toml::de::Error),
+	/// All the invalid options, environment variables included.
+	Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ConfigError::Read(path, e) => write!(f, "Cannot read config file {}: {}", path, e),
+			ConfigError::Parse(path, e) => write!(f, "Invalid config file {}: {}", path, e),
+			ConfigError::Invalid(errors) => {
+				write!(f, "Invalid configuration:")?;
+				for e in errors {
+					write!(f, "\n- {}", e)?;
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Applies the environment variables on top of the config file, collecting
+/// the malformed ones.
+struct EnvOverrides<F> {
+	lookup: F,
+	errors: Vec<String>,
+	warnings: Vec<String>,
+}
+
+impl<F: Fn(&str) -> Option<String>> EnvOverrides<F> {
+	fn parse<T: FromStr>(&mut self, var: &str, expected: &str) -> Option<T> {
+		let value = (self.lookup)(var)?;
+		match value.parse() {
+			Ok(value) => Some(value),
+			Err(_) => {
+				self.errors.push(format!(
+					"Environment variable {} should parse to {}, got `{}`",
+					var, expected, value
+				));
+				None
+			}
+		}
+	}
+
+	fn set<T: FromStr>(&mut self, var: &str, expected: &str, field: &mut T) {
+		if let Some(value) = self.parse(var, expected) {
+			*field = value;
+		}
+	}
+
+	fn set_opt<T: FromStr>(&mut self, var: &str, expected: &str, field: &mut Option<T>) {
+		if let Some(value) = self.parse(var, expected) {
+			*field = Some(value);
+		}
+	}
+
+	/// For the `snake_case` enums, parsed like in the config file.
+	fn set_enum<T: DeserializeOwned>(&mut self, var: &str, expected: &str, field: &mut T) {
+		if let Some(value) = (self.lookup)(var) {
+			match serde_json::from_value(serde_json::Value::String(value.clone())) {
+				Ok(value) => *field = value,
+				Err(_) => self.errors.push(format!(
+					"Environment variable {} should be one of {}, got `{}`",
+					var, expected, value
+				)),
+			}
+		}
+	}
+
+	/// Flags are historically enabled with `1`, any other value disabling
+	/// them. Unknown values are still accepted, with a warning.
+	fn set_flag(&mut self, var: &str, field: &mut bool) {
+		match (self.lookup)(var).as_deref() {
+			None => {}
+			Some("1") | Some("true") => *field = true,
+			Some("0") | Some("false") => *field = false,
+			Some(value) => {
+				self.warnings.push(format!(
+					"Environment variable {} should be 0 or 1, got `{}`, treating it as 0",
+					var, value
+				));
+				*field = false;
+			}
+		}
+	}
+}
+
+impl Config {
+	/// Load the config file at `path` if any, apply the environment
+	/// variables and validate the result. The commands using the database or
+	/// the bulk worker also need [`Config::validate_bulk`].
+	pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+		let config = match path {
+			Some(path) => {
+				let display = path.display().to_string();
+				let content =
+					fs::read_to_string(path).map_err(|e| ConfigError::Read(display.clone(), e))?;
+				toml::from_str(&content).map_err(|e| ConfigError::Parse(display, e))?
+			}
+			None => Config::default(),
+		};
+
+		config.with_env(|var| env::var(var).ok())
+	}
+
+	/// Load the configuration, from the file given in `RCH_CONFIG` if set.
+	pub fn from_env() -> Result<Self, ConfigError> {
+		let path = env::var("RCH_CONFIG").ok();
+		Config::load(path.as_deref().map(Path::new))
+	}
+
+	/// Override the options with the variables found by `lookup`, then
+	/// validate the result, except the bulk options.
+	fn with_env(mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+		let mut env = EnvOverrides {
+			lookup,
+			errors: vec![],
+			warnings: vec![],
+		};
+
+		env.set_enum("RCH_ROLE", "api, worker, all", &mut self.role);
+		env.set("RCH_HTTP_HOST", "an IP address", &mut self.http_host);
+		env.set("PORT", "u16", &mut self.port);
+		env.set("RCH_FROM_EMAIL", "String", &mut self.from_email);
+		env.set(
+			"RCH_SHUTDOWN_TIMEOUT_SECS",
+			"u64",
+			&mut self.shutdown_timeout_secs,
+		);
+		env.set_enum("RCH_LOG_FORMAT", "text, json", &mut self.log_format);
+		env.set_opt("RCH_OTLP_ENDPOINT", "String", &mut self.otlp_endpoint);
+		env.set_opt("RCH_ADMIN_TOKEN", "String", &mut self.admin_token);
+		env.set_opt(
+			"RCH_AUDIT_CALLER_HEADER",
+			"String",
+			&mut self.audit_caller_header,
+		);
+		env.set_flag("RCH_TRUST_FORWARDED_FOR", &mut self.trust_forwarded_for);
+
+		let sentry = &mut self.sentry;
+		env.set_opt("RCH_SENTRY_DSN", "String", &mut sentry.dsn);
+		env.set_opt("HEROKU_APP_NAME", "String", &mut sentry.backend_name);
+		env.set_opt("BACKEND_NAME", "String", &mut sentry.backend_name);
+		env.set_flag("RCH_SENTRY_METRICS", &mut sentry.metrics);
+
+		let redaction = &mut self.redaction;
+		env.set_enum(
+			"RCH_REDACT_EMAILS",
+			"mask, hash, off",
+			&mut redaction.emails,
+		);
+		env.set_opt("RCH_REDACTION_SALT", "String", &mut redaction.salt);
+		env.set_flag(
+			"RCH_REDACT_PROXY_CREDENTIALS",
+			&mut redaction.proxy_credentials,
+		);
+
+		let encryption = &mut self.encryption;
+		env.set_enum(
+			"RCH_ENCRYPTION_MODE",
+			"plaintext, encrypt, hash",
+			&mut encryption.mode,
+		);
+		env.set("RCH_ENCRYPTION_KEYS", "String", &mut encryption.keys);
+
+		let proxies = &mut self.proxies;
+		if let Some(urls) = (env.lookup)("RCH_PROXY_URLS") {
+			let urls = urls
+				.split(',')
+				.map(str::trim)
+				.filter(|u| !u.is_empty())
+				.map(String::from)
+				.collect();
+			proxies.pools.retain(|pool| pool.name != "default");
+			proxies.pools.push(ProxyPoolConfig {
+				name: "default".into(),
+				selection: ProxySelection::default(),
+				urls,
+			});
+			proxies.default_pool.get_or_insert_with(|| "default".into());
+		}
+		env.set_opt(
+			"RCH_PROXY_DEFAULT_POOL",
+			"String",
+			&mut proxies.default_pool,
+		);
+		env.set("RCH_PROXY_MAX_ERRORS", "u32", &mut proxies.max_errors);
+		env.set("RCH_PROXY_EJECTION_SECS", "u64", &mut proxies.ejection_secs);
+		env.set(
+			"RCH_PROXY_HEALTH_CHECK_INTERVAL_SECS",
+			"u64",
+			&mut proxies.health_check_interval_secs,
+		);
+		env.set_opt(
+			"RCH_PROXY_HEALTH_CHECK_TARGET",
+			"String",
+			&mut proxies.health_check_target,
+		);
+
+		let smtp = &mut self.smtp;
+		env.set(
+			"RCH_MAX_SMTP_TIMEOUT_SECS",
+			"u64",
+			&mut smtp.max_smtp_timeout,
+		);
+		env.set("RCH_MAX_SMTP_RETRIES", "usize", &mut smtp.max_retries);
+		env.set_opt("RCH_MAX_DEADLINE_SECS", "u64", &mut smtp.max_deadline);
+
+		let bulk = &mut self.bulk;
+		env.set_flag("RCH_ENABLE_BULK", &mut bulk.enabled);
+		env.set_opt("DATABASE_URL", "String", &mut bulk.database_url);
+		env.set(
+			"RCH_DATABASE_MAX_CONNECTIONS",
+			"u32",
+			&mut bulk.database_max_connections,
+		);
+		env.set_flag("RCH_AUTO_MIGRATE", &mut bulk.auto_migrate);
+		env.set(
+			"RCH_MINIMUM_TASK_CONCURRENCY",
+			"usize",
+			&mut bulk.minimum_task_concurrency,
+		);
+		env.set(
+			"RCH_MAXIMUM_CONCURRENT_TASK_FETCH",
+			"usize",
+			&mut bulk.maximum_concurrent_task_fetch,
+		);
+		env.set(
+			"RCH_HIGH_PRIORITY_TASK_CONCURRENCY",
+			"usize",
+			&mut bulk.high_priority_task_concurrency,
+		);
+		env.set_enum(
+			"RCH_SMTP_PORT_FALLBACK",
+			"on_connection_error, on_unknown, never",
+			&mut bulk.smtp_port_fallback,
+		);
+		env.set_opt(
+			"RCH_READY_MAX_QUEUE_DEPTH",
+			"i64",
+			&mut bulk.ready_max_queue_depth,
+		);
+		env.set_opt("RCH_BULK_RETENTION_DAYS", "u32", &mut bulk.retention_days);
+		env.set_enum(
+			"RCH_BULK_JANITOR_MODE",
+			"delete, anonymize",
+			&mut bulk.janitor_mode,
+		);
+		env.set(
+			"RCH_BULK_JANITOR_INTERVAL_SECS",
+			"u64",
+			&mut bulk.janitor_interval_secs,
+		);
+		env.set(
+			"RCH_GREYLISTING_MAX_ATTEMPTS",
+			"u32",
+			&mut bulk.greylisting_max_attempts,
+		);
+		env.set(
+			"RCH_GREYLISTING_RETRY_DELAY_SECS",
+			"u64",
+			&mut bulk.greylisting_retry_delay_secs,
+		);
+		env.set_opt(
+			"RCH_DOMAIN_CONCURRENCY",
+			"usize",
+			&mut bulk.domain_concurrency,
+		);
+		env.set(
+			"RCH_DOMAIN_MIN_INTERVAL_MS",
+			"u64",
+			&mut bulk.domain_min_interval_ms,
+		);
+		env.set("RCH_DOMAIN_THROTTLE", "String", &mut bulk.domain_throttle);
+
+		self.warnings = env.warnings;
+		let mut errors = env.errors;
+		errors.extend(self.validate());
+		if errors.is_empty() {
+			Ok(self)
+		} else {
+			Err(ConfigError::Invalid(errors))
+		}
+	}
+
+	/// Check the consistency of the options used by all the commands, and
+	/// return the problems found. See also [`Config::validate_bulk`].
+	pub fn validate(&self) -> Vec<String> {
+		let mut errors = vec![];
+		let bulk = &self.bulk;
+
+		if bulk.domain_concurrency == Some(0) {
+			errors.push("bulk.domain_concurrency should be at least 1".into());
+		}
+		if let Err(e) = DomainThrottle::from_config(None, 0, &bulk.domain_throttle) {
+			errors.push(format!("bulk.domain_throttle is malformed: {}", e));
+		}
+		for (i, rule) in self.error_rules.iter().enumerate() {
+			if !rule.has_conditions() {
+				errors.push(format!(
+					"error_rules[{}] should have at least one of step, kind, contains or os_error",
+					i
+				));
+			}
+		}
+		for (i, rule) in self.routing.iter().enumerate() {
+			if rule.domains.is_empty() && rule.mx_hosts.is_empty() {
+				errors.push(format!(
+					"routing[{}] should have at least one of domains or mx_hosts",
+					i
+				));
+			}
+			if let Some(pool) = &rule.proxy_pool {
+				if !self.proxies.pools.iter().any(|p| &p.name == pool) {
+					errors.push(format!(
+						"routing[{}] has an unknown proxy_pool {:?}",
+						i, pool
+					));
+				}
+			}
+			if rule.smtp_ports.as_ref().is_some_and(|p| p.is_empty()) {
+				errors.push(format!("routing[{}] should have at least one smtp_port", i));
+			}
+			if rule.smtp_timeout_secs == Some(0) {
+				errors.push(format!(
+					"routing[{}].smtp_timeout_secs should be at least 1",
+					i
+				));
+			}
+		}
+		if self.redaction.emails == EmailRedaction::Hash
+			&& self
+				.redaction
+				.salt
+				.as_deref()
+				.unwrap_or_default()
+				.is_empty()
+		{
+			errors.push("redaction.emails `hash` requires a salt (RCH_REDACTION_SALT)".into());
+		}
+		if let Err(e) = Keyring::from_config(&self.encryption) {
+			errors.push(format!(
+				"encryption is misconfigured: {} (RCH_ENCRYPTION_KEYS)",
+				e
+			));
+		}
+		if let Err(e) = ProxyPools::from_config(&self.proxies) {
+			errors.push(format!("proxies are misconfigured: {}", e));
+		}
+		if self.smtp.max_smtp_timeout == 0 {
+			errors.push("smtp.max_timeout_secs should be at least 1".into());
+		}
+
+		errors
+	}
+
+	/// Check the options of the database and the bulk worker, which are only
+	/// needed by the commands running them, and return the problems found.
+	pub fn validate_bulk(&self) -> Vec<String> {
+		let mut errors = vec![];
+		let bulk = &self.bulk;
+
+		if self.role == Role::Worker && !bulk.enabled {
+			errors.push("role `worker` requires bulk to be enabled (RCH_ENABLE_BULK=1)".into());
+		}
+		if bulk.enabled && bulk.database_url.is_none() {
+			errors.push("bulk requires a database_url (DATABASE_URL)".into());
+		}
+		if bulk.database_max_connections == 0 {
+			errors.push("bulk.database_max_connections should be at least 1".into());
+		}
+		if bulk.minimum_task_concurrency == 0 {
+			errors.push("bulk.minimum_task_concurrency should be at least 1".into());
+		}
+		if bulk.maximum_concurrent_task_fetch < bulk.minimum_task_concurrency {
+			errors.push(
+				"bulk.maximum_concurrent_task_fetch should be at least bulk.minimum_task_concurrency"
+					.into(),
+			);
+		}
+		if bulk.high_priority_task_concurrency == 0 {
+			errors.push("bulk.high_priority_task_concurrency should be at least 1".into());
+		}
+		if bulk.janitor_interval_secs == 0 {
+			errors.push("bulk.janitor_interval_secs should be at least 1".into());
+		}
+
+		errors
+	}
+
+	/// Log the loading warnings and the options, without the secrets.
+	pub fn log_summary(&self) {
+		for warning in &self.warnings {
+			log::warn!(target: "reacher", "{}", warning);
+		}
+
+		let bulk = &self.bulk;
+		log::info!(
+			target: "reacher",
+			"Configuration: [role={:?}] [http={}:{}] [from_email={}] [log_format={:?}] [otlp={}] [sentry={}] [admin={}] [redaction.emails={:?}] [encryption.mode={:?}] [proxies.pools={}] [proxies.default_pool={}]",
+			self.role,
+			self.http_host,
+			self.port,
+			self.from_email,
+			self.log_format,
+			self.otlp_endpoint.as_deref().unwrap_or("disabled"),
+			if self.sentry.dsn.is_some() { "enabled" } else { "disabled" },
+			if self.admin_token.is_some() { "enabled" } else { "disabled" },
+			self.redaction.emails,
+			self.encryption.mode,
+			self.proxies.pools.len(),
+			self.proxies.default_pool.as_deref().unwrap_or("none"),
+		);
+		log::info!(
+			target: "reacher",
+			"Configuration: [smtp.max_timeout_secs={}] [smtp.max_retries={}] [smtp.max_deadline_secs={:?}]",
+			self.smtp.max_smtp_timeout,
+			self.smtp.max_retries,
+			self.smtp.max_deadline,
+		);
+		if bulk.enabled {
+			log::info!(
+				target: "reacher",
+				"Configuration: [bulk.task_concurrency={}-{}] [bulk.high_priority_task_concurrency={}] [bulk.retention_days={:?}] [bulk.janitor_mode={:?}] [bulk.domain_concurrency={:?}]",
+				bulk.minimum_task_concurrency,
+				bulk.maximum_concurrent_task_fetch,
+				bulk.high_priority_task_concurrency,
+				bulk.retention_days,
+				bulk.janitor_mode,
+				bulk.domain_concurrency,
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+
+	fn with_env(config: Config, vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+		let vars: HashMap<String, String> = vars
+			.iter()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect();
+		config.with_env(|var| vars.get(var).cloned())
+	}
+
+	#[test]
+	fn test_env_overrides_file() {
+		let config: Config = toml::from_str(
+			r#"
+			port = 3000
+			from_email = "file@example.org"
+
+			[bulk]
+			enabled = true
+			database_url = "postgres://localhost/reacher"
+			janitor_mode = "anonymize"
+			"#,
+		)
+		.unwrap();
+		let config = with_env(config, &[("PORT", "4000")]).unwrap();
+
+		assert_eq!(config.port, 4000);
+		assert_eq!(config.from_email, "file@example.org");
+		assert_eq!(config.bulk.janitor_mode, JanitorMode::Anonymize);
+		// Defaults are kept for missing options.
+		assert_eq!(config.bulk.minimum_task_concurrency, 10);
+	}
+
+	#[test]
+	fn test_example_config() {
+		let config: Config = toml::from_str(include_str!("../reacher.example.toml")).unwrap();
+		assert_eq!(
+			serde_json::to_value(config).unwrap(),
+			serde_json::to_value(Config::default()).unwrap()
+		);
+	}
+
+	#[test]
+	fn test_invalid_config() {
+		let err = with_env(
+			Config::default(),
+			&[
+				("RCH_MINIMUM_TASK_CONCURRENCY", "ten"),
+				("RCH_ROLE", "worker"),
+				("RCH_DOMAIN_CONCURRENCY", "0"),
+				("RCH_DOMAIN_THROTTLE", "gmail.com=5"),
+			],
+		)
+		.unwrap_err();
+
+		match err {
+			ConfigError::Invalid(errors) => assert_eq!(
+				errors,
+				vec![
+					"Environment variable RCH_MINIMUM_TASK_CONCURRENCY should parse to usize, got `ten`",
+					"bulk.domain_concurrency should be at least 1",
+					"bulk.domain_throttle is malformed: missing ':' in rule \"gmail.com=5\"",
+				]
+			),
+			e => panic!("Unexpected error {}", e),
+		}
+
+		// The bulk options are only checked by the commands using them.
+		let config = with_env(
+			Config::default(),
+			&[
+				("RCH_ENABLE_BULK", "1"),
+				("RCH_MINIMUM_TASK_CONCURRENCY", "0"),
+			],
+		)
+		.unwrap();
+		assert_eq!(
+			config.validate_bulk(),
+			vec![
+				"bulk requires a database_url (DATABASE_URL)",
+				"bulk.minimum_task_concurrency should be at least 1",
+			]
+		);
+		let config = with_env(Config::default(), &[("RCH_ROLE", "worker")]).unwrap();
+		assert_eq!(
+			config.validate_bulk(),
+			vec!["role `worker` requires bulk to be enabled (RCH_ENABLE_BULK=1)"]
+		);
+
+		assert!(toml::from_str::<Config>("prot = 3000").is_err());
+
+		let config: Config = toml::from_str("[[error_rules]]\ncategory = \"blocked\"").unwrap();
+		assert_eq!(
+			config.validate(),
+			vec!["error_rules[0] should have at least one of step, kind, contains or os_error"]
+		);
+
+		let config: Config =
+			toml::from_str("[[routing]]\nproxy_pool = \"yahoo\"\nsmtp_ports = []").unwrap();
+		assert_eq!(
+			config.validate(),
+			vec![
+				"routing[0] should have at least one of domains or mx_hosts",
+				"routing[0] has an unknown proxy_pool \"yahoo\"",
+				"routing[0] should have at least one smtp_port",
+			]
+		);
+	}
+
+	#[test]
+	fn test_lenient_flags() {
+		let config = with_env(
+			Config::default(),
+			&[("RCH_ENABLE_BULK", "yes"), ("RCH_SENTRY_METRICS", "true")],
+		)
+		.unwrap();
+
+		assert!(!config.bulk.enabled);
+		assert!(config.sentry.metrics);
+		assert_eq!(
+			config.warnings,
+			vec!["Environment variable RCH_ENABLE_BULK should be 0 or 1, got `yes`, treating it as 0"]
+		);
+	}
+}
@@ -0,0 +1,429 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Protection of the email addresses stored in `email_results`.
+//!
+//! Results are stored as is by default. With `encryption.mode = "encrypt"`,
+//! they're encrypted with AES-256-GCM, only `is_reachable` being kept in
+//! clear for the job summaries. With `"hash"`, the address is replaced by a
+//! keyed hash, and can't be recovered.
+//!
+//! The first configured key is used for new results, the other ones are
+//! only used to read older results, so that keys can be rotated. The
+//! `encryption rotate` subcommand rewrites the older results with the first
+//! key. Hashed results can't be rewritten though, and are only found by
+//! `DELETE /v0/emails/{email}` with the key they were hashed with: a key
+//! can only be removed once no hashed result references it, which
+//! `encryption rotate` reports.
+
+use crate::check::normalize_email;
+use crate::config::{EncryptionConfig, RedactionConfig};
+use crate::redact::{mask_strings, EmailRedaction, Redactor};
+use openssl::{base64, hash::MessageDigest, pkey::PKey, rand::rand_bytes, sign::Signer, symm};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// How email addresses are stored in the database.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageMode {
+	/// Store the results as is.
+	#[default]
+	Plaintext,
+	/// Encrypt the results, they're decrypted when downloaded.
+	Encrypt,
+	/// Replace the address by a keyed hash in the results.
+	Hash,
+}
+
+/// Prefix of the encrypted values.
+const SEALED_PREFIX: &str = "enc:v1:";
+/// Prefix of the hashed emails.
+const HASH_PREFIX: &str = "hmac:";
+/// Length of the AES-GCM nonce and tag.
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// An encryption key, with the id under which values it encrypted are
+/// stored.
+struct Key {
+	id: String,
+	key: Vec<u8>,
+	/// Derived from `key`, so that the same key isn't used for both.
+	hash_key: Vec<u8>,
+}
+
+impl std::fmt::Debug for Key {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Key({})", self.id)
+	}
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+	let key = PKey::hmac(key).expect("HMAC key is valid. qed.");
+	let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("HMAC is supported. qed.");
+	signer
+		.sign_oneshot_to_vec(data)
+		.expect("HMAC never fails. qed.")
+}
+
+/// Parse the `id:base64key` keys, separated by commas.
+fn parse_keys(keys: &str) -> Result<Vec<Key>, String> {
+	keys.split(',')
+		.map(str::trim)
+		.filter(|k| !k.is_empty())
+		.map(|k| {
+			let (id, key) = k
+				.split_once(':')
+				.ok_or_else(|| "missing ':' between a key id and the key".to_string())?;
+			if id.is_empty()
+				|| !id
+					.chars()
+					.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+			{
+				return Err(format!("invalid key id {:?}", id));
+			}
+			let key = base64::decode_block(key.trim())
+				.ok()
+				.filter(|k| k.len() == 32)
+				.ok_or_else(|| format!("key {:?} should be 32 bytes encoded in base64", id))?;
+
+			Ok(Key {
+				id: id.into(),
+				hash_key: hmac(&key, b"reacher email hash"),
+				key,
+			})
+		})
+		.collect()
+}
+
+/// A result as written in `email_results`.
+#[derive(Debug, PartialEq)]
+pub struct StoredResult {
+	pub result: Value,
+	pub raw_input: Option<String>,
+	/// Normalized email, or its keyed hash, under which the result is found
+	/// by `DELETE /v0/emails/{email}`.
+	pub normalized_email: Option<String>,
+}
+
+/// The storage mode and the keys.
+#[derive(Debug, Default)]
+pub struct Keyring {
+	mode: StorageMode,
+	/// The first one is used to encrypt.
+	keys: Vec<Key>,
+}
+
+impl Keyring {
+	pub fn from_config(config: &EncryptionConfig) -> Result<Self, String> {
+		let keys = parse_keys(&config.keys)?;
+		if config.mode != StorageMode::Plaintext && keys.is_empty() {
+			return Err(format!(
+				"mode `{}` requires a key",
+				format!("{:?}", config.mode).to_lowercase()
+			));
+		}
+
+		Ok(Keyring {
+			mode: config.mode,
+			keys,
+		})
+	}
+
+	fn seal(&self, key: &Key, plaintext: &str) -> String {
+		let mut nonce = [0; NONCE_LEN];
+		rand_bytes(&mut nonce).expect("OpenSSL RNG never fails. qed.");
+		let mut tag = [0; TAG_LEN];
+		let ciphertext = symm::encrypt_aead(
+			symm::Cipher::aes_256_gcm(),
+			&key.key,
+			Some(&nonce),
+			key.id.as_bytes(),
+			plaintext.as_bytes(),
+			&mut tag,
+		)
+		.expect("AES-GCM encryption never fails. qed.");
+
+		format!(
+			"{}{}:{}",
+			SEALED_PREFIX,
+			key.id,
+			base64::encode_block(&[&nonce[..], &ciphertext, &tag].concat())
+		)
+	}
+
+	fn open(&self, sealed: &str) -> Result<String, String> {
+		let (id, data) = sealed
+			.strip_prefix(SEALED_PREFIX)
+			.and_then(|s| s.split_once(':'))
+			.ok_or("malformed encrypted value")?;
+		let key = self
+			.keys
+			.iter()
+			.find(|k| k.id == id)
+			.ok_or_else(|| format!("unknown key {:?}", id))?;
+		let data = base64::decode_block(data).map_err(|_| "malformed encrypted value")?;
+		if data.len() < NONCE_LEN + TAG_LEN {
+			return Err("malformed encrypted value".into());
+		}
+		let (nonce, rest) = data.split_at(NONCE_LEN);
+		let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+		let plaintext = symm::decrypt_aead(
+			symm::Cipher::aes_256_gcm(),
+			&key.key,
+			Some(nonce),
+			key.id.as_bytes(),
+			ciphertext,
+			tag,
+		)
+		.map_err(|_| format!("failed to decrypt with key {:?}", id))?;
+
+		String::from_utf8(plaintext).map_err(|e| e.to_string())
+	}
+
+	fn hash(key: &Key, email: &str) -> String {
+		let hash = hmac(&key.hash_key, normalize_email(email).as_bytes());
+		let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+
+		format!("{}{}:{}", HASH_PREFIX, key.id, hex)
+	}
+
+	/// The values of `normalized_email` the results of `email` may be stored
+	/// under: in clear, or hashed with any of the keys.
+	pub fn email_lookups(&self, email: &str) -> Vec<String> {
+		std::iter::once(normalize_email(email))
+			.chain(self.keys.iter().map(|k| Self::hash(k, email)))
+			.collect()
+	}
+
+	/// Whether a key with this id is configured.
+	pub fn has_key(&self, id: &str) -> bool {
+		self.keys.iter().any(|k| k.id == id)
+	}
+
+	/// Keyed hash of `email` with the first key, if any, as found in the
+	/// `normalized_email` of its hashed results.
+	pub fn keyed_hash(&self, email: &str) -> Option<String> {
+		self.keys.first().map(|k| Self::hash(k, email))
+	}
+
+	/// Turn a verification result into what's written in the database.
+	pub fn protect(&self, mut result: Value, raw_input: Option<String>) -> StoredResult {
+		let email = result["input"].as_str().map(str::to_string);
+		let key = match (self.mode, self.keys.first()) {
+			(StorageMode::Plaintext, _) | (_, None) => {
+				return StoredResult {
+					result,
+					raw_input,
+					normalized_email: email.as_deref().map(normalize_email),
+				}
+			}
+			(_, Some(key)) => key,
+		};
+		let hash = email.as_deref().map(|e| Self::hash(key, e));
+
+		match self.mode {
+			StorageMode::Encrypt => StoredResult {
+				result: serde_json::json!({
+					"is_reachable": result["is_reachable"],
+					"encrypted": self.seal(key, &result.to_string()),
+				}),
+				raw_input: raw_input.map(|r| self.seal(key, &r)),
+				normalized_email: hash,
+			},
+			_ => {
+				if let Some(obj) = result.as_object_mut() {
+					obj.remove("input");
+				}
+				if let Some(syntax) = result["syntax"].as_object_mut() {
+					syntax.remove("address");
+					syntax.remove("username");
+				}
+				// The address, or its username, may also appear in the SMTP
+				// replies and error messages.
+				if let (Some(email), Some(hash)) = (&email, &hash) {
+					let address = Regex::new(&format!("(?i){}", regex::escape(email.trim())))
+						.expect("Escaped regex is valid. qed.");
+					let redactor = Redactor::new(&RedactionConfig {
+						emails: EmailRedaction::Mask,
+						salt: None,
+						proxy_credentials: false,
+					});
+					mask_strings(&mut result, &|text| {
+						address
+							.split(text)
+							.map(|part| redactor.text_about(part, email.trim()))
+							.collect::<Vec<_>>()
+							.join(hash)
+					});
+				}
+				if let Some(hash) = &hash {
+					result["input"] = hash.as_str().into();
+				}
+
+				StoredResult {
+					result,
+					raw_input: None,
+					normalized_email: hash,
+				}
+			}
+		}
+	}
+
+	/// Read a result written by [`Keyring::protect`], decrypting it if
+	/// needed.
+	pub fn reveal(
+		&self,
+		result: Value,
+		raw_input: Option<String>,
+	) -> Result<(Value, Option<String>), String> {
+		let result = match result["encrypted"].as_str() {
+			Some(sealed) => serde_json::from_str(&self.open(sealed)?).map_err(|e| e.to_string())?,
+			None => result,
+		};
+		let raw_input = match raw_input {
+			Some(r) if r.starts_with(SEALED_PREFIX) => Some(self.open(&r)?),
+			r => r,
+		};
+
+		Ok((result, raw_input))
+	}
+
+	/// Whether a stored result should be rewritten by `encryption rotate`:
+	/// encrypted with an older key, or not matching the current mode.
+	pub fn needs_rotation(&self, result: &Value) -> bool {
+		let sealed = result["encrypted"].as_str();
+		match (self.mode, self.keys.first()) {
+			(StorageMode::Plaintext, _) | (_, None) => sealed.is_some(),
+			(StorageMode::Encrypt, Some(key)) => match sealed {
+				Some(sealed) => !sealed.starts_with(&format!("{}{}:", SEALED_PREFIX, key.id)),
+				// Anonymized results have no input left to protect, and
+				// hashed ones can't be recovered, rehashing them would lose
+				// their address hash.
+				None => result["input"]
+					.as_str()
+					.is_some_and(|i| !i.starts_with(HASH_PREFIX)),
+			},
+			(StorageMode::Hash, Some(_)) => {
+				sealed.is_some()
+					|| result["input"]
+						.as_str()
+						.is_some_and(|i| !i.starts_with(HASH_PREFIX))
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	const KEY_1: &str = "1:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+	const KEY_2: &str = "2:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+	fn keyring(mode: StorageMode, keys: &str) -> Keyring {
+		Keyring::from_config(&EncryptionConfig {
+			mode,
+			keys: keys.into(),
+		})
+		.unwrap()
+	}
+
+	fn result() -> Value {
+		json!({
+			"input": "Someone@Gmail.com",
+			"is_reachable": "invalid",
+			"syntax": { "address": "Someone@Gmail.com", "username": "Someone", "domain": "gmail.com" },
+			"smtp": { "error": { "message": "550 Someone@Gmail.com unknown" } },
+			"misc": { "error": { "message": "550 Someone not found" } }
+		})
+	}
+
+	#[test]
+	fn test_encrypt() {
+		let old = keyring(StorageMode::Encrypt, KEY_1);
+		let stored = old.protect(result(), Some(" Someone@Gmail.com".into()));
+		assert_eq!(stored.result["is_reachable"], "invalid");
+		assert!(!stored.result.to_string().contains("Someone"));
+		assert!(!stored.raw_input.as_ref().unwrap().contains("Someone"));
+		assert_eq!(
+			old.email_lookups("someone@gmail.com")[1],
+			stored.normalized_email.clone().unwrap()
+		);
+
+		// After a rotation, the old results are still readable.
+		let new = keyring(StorageMode::Encrypt, &format!("{},{}", KEY_2, KEY_1));
+		assert!(new.needs_rotation(&stored.result));
+		assert!(!old.needs_rotation(&stored.result));
+		assert_eq!(
+			new.reveal(stored.result.clone(), stored.raw_input.clone())
+				.unwrap(),
+			(result(), Some(" Someone@Gmail.com".into()))
+		);
+
+		// Hashed results can't be recovered, they're left as is.
+		let hashed = keyring(StorageMode::Hash, KEY_1).protect(result(), None);
+		assert!(!new.needs_rotation(&hashed.result));
+		assert!(keyring(StorageMode::Encrypt, KEY_2)
+			.reveal(stored.result, None)
+			.is_err());
+	}
+
+	#[test]
+	fn test_hash() {
+		let keyring = keyring(StorageMode::Hash, KEY_1);
+		let stored = keyring.protect(result(), Some("Someone@Gmail.com".into()));
+		let hash = stored.normalized_email.clone().unwrap();
+		assert!(hash.starts_with("hmac:1:"));
+		assert_eq!(stored.result["input"], hash.as_str());
+		assert_eq!(keyring.keyed_hash(" someone@gmail.com"), Some(hash.clone()));
+		assert_eq!(stored.result["syntax"], json!({ "domain": "gmail.com" }));
+		assert_eq!(
+			stored.result["smtp"]["error"]["message"],
+			format!("550 {} unknown", hash)
+		);
+		assert_eq!(
+			stored.result["misc"]["error"]["message"],
+			"550 *** not found"
+		);
+		assert!(!stored.result.to_string().contains("Someone"));
+		assert_eq!(stored.raw_input, None);
+		assert!(!keyring.needs_rotation(&stored.result));
+		assert!(keyring.needs_rotation(&result()));
+		assert!(keyring.has_key("1"));
+		assert!(!keyring.has_key("2"));
+	}
+
+	#[test]
+	fn test_parse_keys() {
+		assert!(parse_keys("").unwrap().is_empty());
+		assert_eq!(
+			parse_keys(&format!("{}, {}", KEY_1, KEY_2)).unwrap().len(),
+			2
+		);
+		assert!(parse_keys("AAAA").is_err());
+		assert!(parse_keys("1:AAAA").is_err());
+		assert!(parse_keys("a:b:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=").is_err());
+		assert!(Keyring::from_config(&EncryptionConfig {
+			mode: StorageMode::Hash,
+			keys: String::new(),
+		})
+		.is_err());
+	}
+}
@@ -17,8 +17,9 @@
 //! Describe a common response error to be used by all routes, should an error
 //! happen.
 
+use crate::routes::bulk::BulkError;
 use serde::Serialize;
-use warp::{http, reject};
+use warp::{http, reject, Reply};
 
 /// Struct describing an error response.
 #[derive(Serialize, Debug)]
@@ -26,16 +27,107 @@ pub struct ReacherResponseError {
 	#[serde(skip)]
 	code: http::StatusCode,
 	message: String,
+	/// ID of the request, also returned in the `X-Request-Id` header.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	request_id: Option<String>,
+}
+
+impl ReacherResponseError {
+	pub fn new(code: http::StatusCode, message: impl Into<String>) -> Self {
+		ReacherResponseError {
+			code,
+			message: message.into(),
+			request_id: None,
+		}
+	}
 }
 
 impl reject::Reject for ReacherResponseError {}
 
-/// This function receives a `Rejection` and tries to return a custom value,
-/// otherwise simply passes the rejection along.
-pub async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
-	if let Some(err) = err.find::<ReacherResponseError>() {
-		Ok((warp::reply::with_status(warp::reply::json(err), err.code),))
+/// This function receives a `Rejection` and tries to turn it into a JSON
+/// error response carrying the request ID, otherwise simply passes the
+/// rejection along.
+pub fn handle_rejection(
+	err: warp::Rejection,
+	request_id: &str,
+) -> Result<warp::reply::Response, warp::Rejection> {
+	let mut response_error = if let Some(err) = err.find::<ReacherResponseError>() {
+		ReacherResponseError::new(err.code, err.message.clone())
+	} else if let Some(err) = err.find::<BulkError>() {
+		match err {
+			BulkError::EmptyInput => {
+				ReacherResponseError::new(http::StatusCode::BAD_REQUEST, err.to_string())
+			}
+			BulkError::JobInProgress => {
+				ReacherResponseError::new(http::StatusCode::CONFLICT, err.to_string())
+			}
+			// Database, driver and decryption errors stay in the logs.
+			BulkError::Db(_) | BulkError::Csv(_) | BulkError::Json(_) | BulkError::Decrypt(_) => {
+				log::error!(
+					target: "reacher",
+					"Request [request_id={}] failed with [error={}]",
+					request_id,
+					err
+				);
+				ReacherResponseError::new(
+					http::StatusCode::INTERNAL_SERVER_ERROR,
+					"Internal server error",
+				)
+			}
+		}
+	} else if let Some(err) = err.find::<warp::body::BodyDeserializeError>() {
+		ReacherResponseError::new(http::StatusCode::BAD_REQUEST, err.to_string())
+	} else if let Some(err) = err.find::<reject::PayloadTooLarge>() {
+		ReacherResponseError::new(http::StatusCode::PAYLOAD_TOO_LARGE, err.to_string())
+	} else if let Some(err) = err.find::<reject::UnsupportedMediaType>() {
+		ReacherResponseError::new(http::StatusCode::UNSUPPORTED_MEDIA_TYPE, err.to_string())
+	} else if let Some(err) = err.find::<reject::MethodNotAllowed>() {
+		ReacherResponseError::new(http::StatusCode::METHOD_NOT_ALLOWED, err.to_string())
+	} else if err.is_not_found() {
+		ReacherResponseError::new(http::StatusCode::NOT_FOUND, "Not found")
 	} else {
-		Err(err)
+		return Err(err);
+	};
+	response_error.request_id = Some(request_id.into());
+
+	Ok(
+		warp::reply::with_status(warp::reply::json(&response_error), response_error.code)
+			.into_response(),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn reply(err: BulkError) -> (http::StatusCode, String) {
+		let response = handle_rejection(reject::custom(err), "abc-123").unwrap();
+		let status = response.status();
+		let body =
+			futures::executor::block_on(warp::hyper::body::to_bytes(response.into_body())).unwrap();
+
+		(status, String::from_utf8(body.to_vec()).unwrap())
+	}
+
+	#[test]
+	fn test_bulk_error_status() {
+		assert_eq!(
+			reply(BulkError::EmptyInput),
+			(
+				http::StatusCode::BAD_REQUEST,
+				r#"{"message":"Empty input","request_id":"abc-123"}"#.into()
+			)
+		);
+		assert_eq!(
+			reply(BulkError::JobInProgress).0,
+			http::StatusCode::CONFLICT
+		);
+		assert_eq!(
+			reply(BulkError::Decrypt("unknown key \"1\"".into())),
+			(
+				http::StatusCode::INTERNAL_SERVER_ERROR,
+				r#"{"message":"Internal server error","request_id":"abc-123"}"#.into()
+			)
+		);
 	}
 }
@@ -14,7 +14,17 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod bulk_file;
 pub mod check;
+pub mod classify;
+pub mod config;
+pub mod encryption;
 mod errors;
+pub mod metrics;
+pub mod proxy;
+pub mod redact;
 pub mod routes;
+pub mod routing;
 pub mod sentry_util;
+pub mod state;
+pub mod telemetry;
@@ -14,118 +14,511 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-//! Main entry point of the `reacher_backend` binary. It has two `main`
-//! functions, depending on whether the `bulk` feature is enabled or not.
+//! Main entry point of the `reacher_backend` binary. Without subcommand, it
+//! runs the HTTP server and/or the bulk worker, depending on the configured
+//! role.
 
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
-use reacher_backend::routes::{bulk::email_verification_task, create_routes};
+use reacher_backend::bulk_file::{verify_file, BulkFileOptions, InputFormat, OutputFormat};
+use reacher_backend::check::SmtpLimits;
+use reacher_backend::config::{BulkConfig, Config, ConfigError, Role};
+use reacher_backend::metrics::spawn_bulk_metrics;
+use reacher_backend::proxy::check_email_with_proxy;
+use reacher_backend::routes::{
+	bulk::{
+		email_verification_task, hash_key_usage, migration_status, rotate_results,
+		undo_last_migration, JobPriority, JobRunners, TaskTracker, MIGRATOR,
+	},
+	check_email::post::EndpointRequest,
+	create_probe_routes, create_routes,
+};
+use reacher_backend::routing::proxy_pool_for;
 use reacher_backend::sentry_util::{setup_sentry, CARGO_PKG_VERSION};
+use reacher_backend::state::AppState;
+use reacher_backend::telemetry::setup_telemetry;
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
 use sqlxmq::{JobRegistry, OwnedHandle};
-use std::{env, net::IpAddr};
+use std::{future::Future, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::watch;
 use warp::Filter;
 
-/// Run a HTTP server using warp with bulk endpoints.
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Reacher backend: email verification HTTP server and bulk worker.
+#[derive(Debug, Parser)]
+#[clap(version)]
+struct Cli {
+	/// Path to the TOML configuration file. Overrides `RCH_CONFIG`.
+	#[clap(long, global = true, value_name = "PATH")]
+	config: Option<PathBuf>,
+	#[clap(subcommand)]
+	command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+	/// Run the HTTP server and/or the bulk worker, depending on the
+	/// configured role. This is the default.
+	Serve,
+	/// Only run the bulk worker, whatever the configured role.
+	Worker,
+	/// Manage the database migrations.
+	#[clap(subcommand)]
+	Migrate(MigrateCommand),
+	/// Verify a single email and print the result as JSON.
+	Check {
+		/// The email to verify.
+		email: String,
+	},
+	/// Verify a file of emails locally, without the HTTP server nor the
+	/// database, and write the results to another file.
+	BulkFile {
+		/// CSV file with an `email` column, or one email per line.
+		input: PathBuf,
+		/// Defaults to CSV if the file has a `.csv` extension.
+		#[clap(long, value_enum)]
+		input_format: Option<InputFormat>,
+		/// Where to write the results.
+		#[clap(short, long)]
+		output: PathBuf,
+		#[clap(long, value_enum, default_value = "csv")]
+		output_format: OutputFormat,
+		/// Defaults to the output path followed by `.checkpoint`.
+		#[clap(long)]
+		checkpoint: Option<PathBuf>,
+		/// Continue an interrupted run, skipping the emails in the checkpoint.
+		#[clap(long)]
+		resume: bool,
+		/// Number of emails verified at the same time.
+		#[clap(long, default_value = "10")]
+		concurrency: usize,
+		/// SMTP ports to try, in order, according to `bulk.smtp_port_fallback`.
+		#[clap(long, value_delimiter = ',', default_value = "25")]
+		smtp_ports: Vec<u16>,
+	},
+	/// Manage the encryption of the stored results.
+	#[clap(subcommand)]
+	Encryption(EncryptionCommand),
+	/// Inspect the configuration.
+	#[clap(subcommand)]
+	Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum MigrateCommand {
+	/// Apply all the pending migrations.
+	Up,
+	/// Revert the latest applied migration.
+	Down,
+	/// List the migrations and whether they are applied.
+	Status,
+}
+
+#[derive(Debug, Subcommand)]
+enum EncryptionCommand {
+	/// Rewrite the results stored with an older key, or before a change of
+	/// `encryption.mode`, and list the keys still needed by hashed results.
+	Rotate,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+	/// Load and validate the configuration, then exit.
+	Validate,
+}
+
+/// Print a configuration error and exit.
+fn exit_with(e: ConfigError) -> ! {
+	eprintln!("{}", e);
+	std::process::exit(1);
+}
+
+/// Exit if the options of the database and the bulk worker are invalid, for
+/// the commands using them.
+fn validate_bulk(config: &Config) {
+	let errors = config.validate_bulk();
+	if !errors.is_empty() {
+		exit_with(ConfigError::Invalid(errors));
+	}
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-	init_logger();
+async fn main() -> Result<(), BoxError> {
+	let cli = Cli::parse();
+
+	// Read from .env file if present.
+	let _ = dotenv();
+	let config = match &cli.config {
+		Some(path) => Config::load(Some(path)),
+		None => Config::from_env(),
+	};
+	let mut config = config.unwrap_or_else(|e| exit_with(e));
+	let state = Arc::new(AppState::new(&config));
+
+	match cli.command.unwrap_or(Command::Serve) {
+		Command::Serve => {
+			validate_bulk(&config);
+			serve(config, state).await
+		}
+		Command::Worker => {
+			config.role = Role::Worker;
+			validate_bulk(&config);
+			serve(config, state).await
+		}
+		Command::Migrate(command) => {
+			validate_bulk(&config);
+			migrate(&config.bulk, command).await
+		}
+		Command::Check { email } => {
+			let mut deadline = config.smtp.deadline(None).map(Duration::from_secs);
+			let rule = state.router.route(&email, &mut deadline).await;
+			let input = EndpointRequest::new(email).into_input(&config, rule);
+			let pool = proxy_pool_for(&state.proxies, rule);
+			let (output, _) = check_email_with_proxy(&input, pool, deadline, &state).await;
+			println!(
+				"{}",
+				serde_json::to_string_pretty(&state.classifier.to_json(&output))?
+			);
+			Ok(())
+		}
+		Command::BulkFile {
+			input,
+			input_format,
+			output,
+			output_format,
+			checkpoint,
+			resume,
+			concurrency,
+			smtp_ports,
+		} => {
+			let _telemetry = setup_telemetry(&config, state.redactor.clone())?;
+			let opts = BulkFileOptions {
+				input_format: input_format.unwrap_or_else(|| InputFormat::from_path(&input)),
+				input,
+				output,
+				output_format,
+				checkpoint,
+				resume,
+				concurrency,
+				smtp_ports,
+			};
+			let summary = verify_file(&config, &state, opts).await?;
+			if summary.failed > 0 {
+				return Err(format!(
+					"{} rows have no result, pass --resume to retry them.",
+					summary.failed
+				)
+				.into());
+			}
+			Ok(())
+		}
+		Command::Encryption(EncryptionCommand::Rotate) => {
+			let pool = connect_once(&config.bulk).await?;
+			let count = rotate_results(&pool, &state.keyring).await?;
+			println!("Rewrote {} results.", count);
+
+			// Hashed results keep their key, which can't be removed then.
+			let mut missing = vec![];
+			for (key_id, count) in hash_key_usage(&pool).await? {
+				if state.keyring.has_key(&key_id) {
+					println!("Key {} hashes {} results, keep it.", key_id, count);
+				} else {
+					missing.push(format!("{} ({} results)", key_id, count));
+				}
+			}
+			if !missing.is_empty() {
+				return Err(format!(
+					"Removed keys still hash stored results, which can't be erased by address anymore: {}",
+					missing.join(", ")
+				)
+				.into());
+			}
+			Ok(())
+		}
+		Command::Config(ConfigCommand::Validate) => {
+			// Everything `serve` would check.
+			validate_bulk(&config);
+			println!("Configuration is valid.");
+			Ok(())
+		}
+	}
+}
+
+/// Connect to the database for a one-off command.
+async fn connect_once(config: &BulkConfig) -> Result<Pool<Postgres>, BoxError> {
+	let pg_conn = config
+		.database_url
+		.as_deref()
+		.ok_or("DATABASE_URL must be set to run this command.")?;
+
+	Ok(PgPoolOptions::new()
+		.max_connections(1)
+		.connect(pg_conn)
+		.await?)
+}
+
+/// Run the `migrate` subcommands.
+async fn migrate(config: &BulkConfig, command: MigrateCommand) -> Result<(), BoxError> {
+	let pool = connect_once(config).await?;
+
+	match command {
+		MigrateCommand::Up => {
+			MIGRATOR.run(&pool).await?;
+			println!("All migrations applied.");
+		}
+		MigrateCommand::Down => match undo_last_migration(&pool).await? {
+			Some(version) => println!("Reverted migration {}.", version),
+			None => println!("No migration to revert."),
+		},
+		MigrateCommand::Status => {
+			for m in migration_status(&pool).await? {
+				println!(
+					"{:<8} {} {}",
+					if m.applied { "applied" } else { "pending" },
+					m.version,
+					m.description
+				);
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Run a HTTP server using warp with bulk endpoints, and/or the bulk worker.
+async fn serve(config: Config, state: Arc<AppState>) -> Result<(), BoxError> {
+	let config = Arc::new(config);
+
+	let _telemetry = setup_telemetry(&config, state.redactor.clone())?;
+	log::info!(target: "reacher", "Running Reacher v{}", CARGO_PKG_VERSION);
+	config.log_summary();
 
 	// Setup sentry bug tracking.
-	let _guard = setup_sentry();
+	let _guard = setup_sentry(&config.sentry, state.redactor.clone());
 
-	let is_bulk_enabled = env::var("RCH_ENABLE_BULK").unwrap_or_else(|_| "0".into()) == "1";
-	if is_bulk_enabled {
+	let role = config.role;
+
+	// Tasks running in this process, to be drained on shutdown.
+	let tracker = Arc::new(TaskTracker::default());
+	let mut runners = None;
+	let mut janitor = None;
+	// Readmit the ejected proxies once they work again.
+	let health_checks = {
+		let state = state.clone();
+		tokio::spawn(async move { state.proxies.run_health_checks().await })
+	};
+
+	let pool = if config.bulk.enabled {
 		log::info!(target: "reacher", "Bulk endpoints enabled.");
-		let pool = create_db().await?;
-		let _registry = create_job_registry(&pool).await?;
-		let routes = create_routes(Some(pool));
-		run_warp_server(routes).await?;
+		let pool = create_db(&config.bulk).await?;
+
+		// Workers on different hosts share the tasks through the Postgres
+		// queue. They also clean up expired bulk jobs, and refresh the bulk
+		// queue metrics, in the background.
+		if role.runs_worker() {
+			runners = Some(Arc::new(JobRunners::new(
+				create_job_registry(
+					&config.bulk,
+					config.smtp,
+					&pool,
+					tracker.clone(),
+					state.clone(),
+				)
+				.await?,
+			)));
+			janitor = Some(config.bulk.janitor().spawn(pool.clone()));
+			spawn_bulk_metrics(pool.clone());
+		}
+
+		Some(pool)
 	} else {
-		let routes = create_routes(None);
-		run_warp_server(routes).await?;
+		None
+	};
+
+	let (shutdown_tx, shutdown_rx) = watch::channel(());
+	let mut server = {
+		let addr = (config.http_host, config.port).into();
+		let mut shutdown_rx = shutdown_rx.clone();
+		let shutdown = async move {
+			let _ = shutdown_rx.changed().await;
+		};
+		// Worker-only processes still expose their probes and metrics.
+		if role.runs_api() {
+			tokio::spawn(run_warp_server(
+				addr,
+				create_routes(config.clone(), state.clone(), pool, runners.clone()),
+				shutdown,
+			))
+		} else {
+			tokio::spawn(run_warp_server(
+				addr,
+				create_probe_routes(config.clone(), pool, runners.clone()),
+				shutdown,
+			))
+		}
+	};
+
+	// Wait for a shutdown signal, unless the server stops by itself.
+	tokio::select! {
+		res = &mut server => return res?,
+		_ = shutdown_signal() => {},
+	}
+
+	let deadline = config.shutdown_timeout_secs;
+	log::info!(
+		target: "reacher",
+		"Shutting down, waiting up to {}s for in-flight checks and [tasks={}].",
+		deadline,
+		tracker.running()
+	);
+
+	// Stop accepting requests and fetching new tasks. In-flight requests and
+	// running tasks are not cancelled.
+	let _ = shutdown_tx.send(());
+	if let Some(janitor) = janitor {
+		janitor.abort();
+	}
+	health_checks.abort();
+	if let Some(runners) = runners {
+		runners.stop().await;
+	}
+
+	let drain = async {
+		server.await??;
+		tracker.wait_idle().await;
+
+		Ok::<(), BoxError>(())
+	};
+	match tokio::time::timeout(Duration::from_secs(deadline), drain).await {
+		Ok(res) => res?,
+		// Unfinished tasks will be retried by another worker.
+		Err(_) => log::warn!(
+			target: "reacher",
+			"Shutdown deadline exceeded, dropping [tasks={}].",
+			tracker.running()
+		),
 	}
 
+	log::info!(target: "reacher", "Shutdown complete.");
 	Ok(())
 }
 
-fn init_logger() {
-	// Read from .env file if present.
-	let _ = dotenv();
-	env_logger::init();
-	log::info!(target: "reacher", "Running Reacher v{}", CARGO_PKG_VERSION);
+/// Resolve when the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+	let ctrl_c = async {
+		tokio::signal::ctrl_c()
+			.await
+			.expect("Failed to install SIGINT handler.");
+	};
+
+	#[cfg(unix)]
+	let terminate = async {
+		tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+			.expect("Failed to install SIGTERM handler.")
+			.recv()
+			.await;
+	};
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		_ = ctrl_c => {},
+		_ = terminate => {},
+	}
 }
 
 /// Create a DB pool.
-pub async fn create_db() -> Result<Pool<Postgres>, sqlx::Error> {
-	let pg_conn =
-		env::var("DATABASE_URL").expect("Environment variable DATABASE_URL should be set");
-	let pg_max_conn = env::var("RCH_DATABASE_MAX_CONNECTIONS").map_or(5, |var| {
-		var.parse::<u32>()
-			.expect("Environment variable RCH_DATABASE_MAX_CONNECTIONS should parse to u32")
-	});
+pub async fn create_db(config: &BulkConfig) -> Result<Pool<Postgres>, sqlx::Error> {
+	let pg_conn = config
+		.database_url
+		.as_deref()
+		.expect("Database URL is validated on load. qed.");
 
 	// create connection pool with database
 	// connection pool internally the shared db connection
 	// with arc so it can safely be cloned and shared across threads
 	let pool = PgPoolOptions::new()
-		.max_connections(pg_max_conn)
-		.connect(pg_conn.as_str())
+		.max_connections(config.database_max_connections)
+		.connect(pg_conn)
 		.await?;
 
-	sqlx::migrate!("./migrations").run(&pool).await?;
+	// Otherwise, the migrations are run with `reacher_backend migrate up`.
+	if config.auto_migrate {
+		MIGRATOR.run(&pool).await?;
+	}
 
 	Ok(pool)
 }
 
-/// Create a job registry with one task: the email verification task.
-async fn create_job_registry(pool: &Pool<Postgres>) -> Result<OwnedHandle, sqlx::Error> {
-	let min_task_conc = env::var("RCH_MINIMUM_TASK_CONCURRENCY").map_or(10, |var| {
-		var.parse::<usize>()
-			.expect("Environment variable RCH_MINIMUM_TASK_CONCURRENCY should parse to usize")
-	});
-	let max_conc_task_fetch = env::var("RCH_MAXIMUM_CONCURRENT_TASK_FETCH").map_or(20, |var| {
-		var.parse::<usize>()
-			.expect("Environment variable RCH_MAXIMUM_CONCURRENT_TASK_FETCH should parse to usize")
-	});
-
-	// registry needs to be given list of jobs it can accept
-	let registry = JobRegistry::new(&[email_verification_task]);
+/// Create the job runners for the email verification task: one runner
+/// polling all channels, and one dedicated to the high priority channel.
+async fn create_job_registry(
+	config: &BulkConfig,
+	limits: SmtpLimits,
+	pool: &Pool<Postgres>,
+	tracker: Arc<TaskTracker>,
+	state: Arc<AppState>,
+) -> Result<Vec<OwnedHandle>, sqlx::Error> {
+	// The per-domain throttle is shared by all tasks of this process.
+	let throttle = Arc::new(config.domain_throttle());
+	let retry = config.greylisting_retry();
+	let new_registry = || {
+		// registry needs to be given list of jobs it can accept
+		let mut registry = JobRegistry::new(&[email_verification_task]);
+		registry.set_context(throttle.clone());
+		registry.set_context(retry);
+		registry.set_context(tracker.clone());
+		registry.set_context(limits);
+		registry.set_context(state.clone());
+		registry
+	};
 
 	// create runner for the message queue associated
 	// with this job registry
-	let registry = registry
+	let registry = new_registry()
 		// Create a job runner using the connection pool.
 		.runner(pool)
 		// Here is where you can configure the job runner
 		// Aim to keep 10-20 jobs running at a time.
-		.set_concurrency(min_task_conc, max_conc_task_fetch)
+		.set_concurrency(
+			config.minimum_task_concurrency,
+			config.maximum_concurrent_task_fetch,
+		)
 		// Start the job runner in the background.
 		.run()
 		.await?;
 
-	Ok(registry)
+	// High priority tasks are also picked up by the runner above, this one
+	// only adds capacity reserved to them.
+	let high_priority_registry = new_registry()
+		.runner(pool)
+		.set_channel_names(&[JobPriority::High.channel_name()])
+		.set_concurrency(
+			config.high_priority_task_concurrency,
+			config.high_priority_task_concurrency,
+		)
+		.run()
+		.await?;
+
+	Ok(vec![registry, high_priority_registry])
 }
 
+/// Run the HTTP server until `shutdown` resolves, then wait for the in-flight
+/// requests to finish.
 async fn run_warp_server(
+	addr: SocketAddr,
 	routes: impl Filter<Extract = impl warp::Reply, Error = warp::Rejection>
 		+ Clone
 		+ Send
 		+ Sync
 		+ 'static,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-	let host = env::var("RCH_HTTP_HOST")
-		.unwrap_or_else(|_| "127.0.0.1".into())
-		.parse::<IpAddr>()
-		.expect("Environment variable RCH_HTTP_HOST is malformed.");
-	let port = env::var("PORT")
-		.map(|port| {
-			port.parse::<u16>()
-				.expect("Environment variable PORT is malformed.")
-		})
-		.unwrap_or(8080);
-	log::info!(target: "reacher", "Server is listening on {}:{}.", host, port);
-
-	warp::serve(routes).run((host, port)).await;
+	shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), BoxError> {
+	let (addr, server) = warp::serve(routes).try_bind_with_graceful_shutdown(addr, shutdown)?;
+	log::info!(target: "reacher", "Server is listening on {}.", addr);
+
+	server.await;
 
 	Ok(())
 }
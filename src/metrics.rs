@@ -0,0 +1,271 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Prometheus metrics, exposed on `GET /metrics`.
+//!
+//! The verification and HTTP metrics are recorded by this process as they
+//! happen. The bulk queue metrics are read from the database in the
+//! background every [`BULK_METRICS_INTERVAL`], as the queue is shared by all
+//! the workers, and scrapes shouldn't hit the database. Only the worker
+//! processes refresh them, not every API replica.
+
+use crate::classify::ErrorCategory;
+use crate::routes::route_label;
+use check_if_email_exists::CheckEmailOutput;
+use once_cell::sync::Lazy;
+use prometheus::{
+	histogram_opts, opts, Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Registry,
+	TextEncoder,
+};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Buckets of the verification duration histogram, in seconds. An SMTP
+/// verification can take up to 2 x `SMTP_TIMEOUT` by default.
+const VERIFICATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// Interval between two refreshes of the bulk queue metrics.
+const BULK_METRICS_INTERVAL: Duration = Duration::from_secs(30);
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+static VERIFICATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+	register(IntCounterVec::new(
+		opts!(
+			"reacher_verifications_total",
+			"Number of email verifications, by result."
+		),
+		&["is_reachable"],
+	))
+});
+
+static VERIFICATION_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+	register(HistogramVec::new(
+		histogram_opts!(
+			"reacher_verification_duration_seconds",
+			"Duration of email verifications, by result.",
+			VERIFICATION_BUCKETS.to_vec()
+		),
+		&["is_reachable"],
+	))
+});
+
+static VERIFICATION_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+	register(IntCounterVec::new(
+		opts!(
+			"reacher_verification_errors_total",
+			"Number of email verifications which returned an error, by email provider and error kind."
+		),
+		&["domain_class", "kind"],
+	))
+});
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+	register(IntCounterVec::new(
+		opts!(
+			"reacher_http_requests_total",
+			"Number of HTTP requests, by route, method and status."
+		),
+		&["route", "method", "status"],
+	))
+});
+
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+	register(HistogramVec::new(
+		histogram_opts!(
+			"reacher_http_request_duration_seconds",
+			"Duration of HTTP requests, by route.",
+			VERIFICATION_BUCKETS.to_vec()
+		),
+		&["route"],
+	))
+});
+
+static BULK_QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| {
+	register(IntGauge::new(
+		"reacher_bulk_queue_depth",
+		"Number of bulk tasks ready to be run.",
+	))
+});
+
+static BULK_JOBS: Lazy<IntGaugeVec> = Lazy::new(|| {
+	register(IntGaugeVec::new(
+		opts!(
+			"reacher_bulk_jobs",
+			"Number of bulk jobs, by status: `running` jobs still have tasks in the queue."
+		),
+		&["status"],
+	))
+});
+
+/// Register a metric in our registry.
+fn register<M: prometheus::core::Collector + Clone + 'static>(
+	metric: Result<M, prometheus::Error>,
+) -> M {
+	let metric = metric.expect("Metric options are valid. qed.");
+	REGISTRY
+		.register(Box::new(metric.clone()))
+		.expect("Metric is registered only once. qed.");
+
+	metric
+}
+
+/// Group domains by email provider, to keep the number of label values
+/// bounded. The MX records are used when available, so that e.g. Google
+/// Workspace domains count as `google`.
+fn domain_class(output: &CheckEmailOutput) -> &'static str {
+	let mut hosts = vec![output.syntax.domain.to_lowercase()];
+	if let Ok(mx) = &output.mx {
+		if let Ok(lookup) = &mx.lookup {
+			hosts.extend(lookup.iter().map(|r| r.exchange().to_string()));
+		}
+	}
+
+	let has_suffix = |suffixes: &[&str]| {
+		hosts.iter().any(|host| {
+			let host = host.trim_end_matches('.');
+			suffixes
+				.iter()
+				.any(|s| host == *s || host.ends_with(&format!(".{}", s)))
+		})
+	};
+
+	if has_suffix(&["gmail.com", "googlemail.com", "google.com"]) {
+		"google"
+	} else if has_suffix(&[
+		"hotmail.com",
+		"outlook.com",
+		"live.com",
+		"msn.com",
+		"protection.outlook.com",
+	]) {
+		"microsoft"
+	} else if has_suffix(&["yahoo.com", "yahoodns.net", "aol.com", "ymail.com"]) {
+		"yahoo"
+	} else {
+		"other"
+	}
+}
+
+/// Record a finished email verification, and the category of its error if
+/// any.
+pub fn observe_verification(
+	output: &CheckEmailOutput,
+	category: Option<ErrorCategory>,
+	duration: Duration,
+) {
+	let is_reachable = format!("{:?}", output.is_reachable).to_lowercase();
+	VERIFICATIONS.with_label_values(&[&is_reachable]).inc();
+	VERIFICATION_DURATION
+		.with_label_values(&[&is_reachable])
+		.observe(duration.as_secs_f64());
+
+	if let Some(category) = category {
+		VERIFICATION_ERRORS
+			.with_label_values(&[domain_class(output), category.as_str()])
+			.inc();
+	}
+}
+
+/// Record a served HTTP request. Meant to be used with `warp::log::custom`.
+pub fn observe_http_request(info: warp::log::Info) {
+	// Unknown paths would add a label value per path.
+	let route = if info.status() == warp::http::StatusCode::NOT_FOUND {
+		"unmatched".into()
+	} else {
+		route_label(info.path())
+	};
+	HTTP_REQUESTS
+		.with_label_values(&[&route, info.method().as_str(), info.status().as_str()])
+		.inc();
+	HTTP_REQUEST_DURATION
+		.with_label_values(&[&route])
+		.observe(info.elapsed().as_secs_f64());
+}
+
+/// Update the bulk queue metrics from the database.
+async fn update_bulk_metrics(conn_pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+	let depth = sqlx::query_scalar::<_, i64>(
+		"SELECT COUNT(*) FROM mq_msgs WHERE id != uuid_nil() AND attempt_at <= NOW()",
+	)
+	.fetch_one(conn_pool)
+	.await?;
+	BULK_QUEUE_DEPTH.set(depth);
+
+	// Jobs with tasks left in the queue, found with the `mq_payloads_job_id`
+	// index, instead of counting the results of all the retained jobs.
+	let running = sqlx::query_scalar::<_, i64>(
+		r#"
+		SELECT COUNT(DISTINCT (payload_json ->> 'id')::INTEGER)
+		FROM mq_payloads JOIN mq_msgs USING (id)
+		WHERE mq_msgs.attempts > 0
+		"#,
+	)
+	.fetch_one(conn_pool)
+	.await?;
+	let total = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM bulk_jobs")
+		.fetch_one(conn_pool)
+		.await?;
+	let completed = (total - running).max(0);
+	BULK_JOBS.with_label_values(&["running"]).set(running);
+	BULK_JOBS.with_label_values(&["completed"]).set(completed);
+
+	Ok(())
+}
+
+/// Refresh the bulk queue metrics from the database in the background,
+/// forever.
+pub fn spawn_bulk_metrics(conn_pool: sqlx::PgPool) -> JoinHandle<()> {
+	tokio::spawn(async move {
+		loop {
+			if let Err(e) = update_bulk_metrics(&conn_pool).await {
+				log::error!(
+					target: "reacher",
+					"Failed to update bulk metrics with [error={}]",
+					e
+				);
+			}
+
+			tokio::time::sleep(BULK_METRICS_INTERVAL).await;
+		}
+	})
+}
+
+/// Render all the metrics in the Prometheus text format.
+pub fn render() -> Vec<u8> {
+	let mut buffer = vec![];
+	TextEncoder::new()
+		.encode(&REGISTRY.gather(), &mut buffer)
+		.expect("Encoding metrics to a Vec never fails. qed.");
+
+	buffer
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_domain_class() {
+		let mut output = CheckEmailOutput::default();
+		output.syntax.domain = "googlemail.com".into();
+		assert_eq!(domain_class(&output), "google");
+		output.syntax.domain = "eu.outlook.com".into();
+		assert_eq!(domain_class(&output), "microsoft");
+		output.syntax.domain = "notoutlook.com".into();
+		assert_eq!(domain_class(&output), "other");
+	}
+}
@@ -0,0 +1,467 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Server-side pools of SOCKS5 proxies.
+//!
+//! Verifications which don't carry their own proxy get one from a pool, in
+//! turn or the least used one. A proxy is ejected from its pool after
+//! `max_errors` SOCKS errors in a row, until it passes a health check or
+//! `ejection_secs` have passed.
+
+use crate::check::check_email_with_timeout;
+use crate::config::ProxiesConfig;
+use crate::state::AppState;
+use check_if_email_exists::{
+	smtp::SmtpError, CheckEmailInput, CheckEmailInputProxy, CheckEmailOutput,
+};
+use fast_socks5::client::{Config as Socks5Config, Socks5Stream};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicU32, AtomicUsize, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant},
+};
+
+/// Time for a proxy to connect to the health check target.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a pool picks the proxy of each verification.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxySelection {
+	/// Each proxy in turn.
+	#[default]
+	RoundRobin,
+	/// The proxy with the fewest verifications running.
+	LeastUsed,
+}
+
+/// A `[[proxies.pools]]` entry.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyPoolConfig {
+	pub name: String,
+	pub selection: ProxySelection,
+	/// As `socks5://[username:password@]host:port`.
+	pub urls: Vec<String>,
+}
+
+/// Parse a `socks5://[username:password@]host:port` URL.
+pub fn parse_proxy_url(url: &str) -> Result<CheckEmailInputProxy, String> {
+	let rest = url
+		.trim()
+		.strip_prefix("socks5://")
+		.ok_or_else(|| "should start with socks5://".to_string())?;
+	let (credentials, address) = match rest.rsplit_once('@') {
+		Some((credentials, address)) => (Some(credentials), address),
+		None => (None, rest),
+	};
+	let (host, port) = address
+		.trim_end_matches('/')
+		.rsplit_once(':')
+		.ok_or_else(|| "missing the port".to_string())?;
+	let port = port
+		.parse()
+		.map_err(|_| format!("invalid port {:?}", port))?;
+	if host.is_empty() {
+		return Err("missing the host".into());
+	}
+	let (username, password) = match credentials.map(|c| c.split_once(':')) {
+		Some(Some((username, password))) => (Some(username.into()), Some(password.into())),
+		Some(None) => return Err("credentials should be username:password".into()),
+		None => (None, None),
+	};
+
+	Ok(CheckEmailInputProxy {
+		host: host.into(),
+		port,
+		username,
+		password,
+	})
+}
+
+/// How a proxy is recorded in the results and logs, without its
+/// credentials.
+pub fn proxy_id(proxy: &CheckEmailInputProxy) -> String {
+	format!("{}:{}", proxy.host, proxy.port)
+}
+
+/// A proxy of a pool, with its health.
+#[derive(Debug)]
+struct PooledProxy {
+	id: String,
+	proxy: CheckEmailInputProxy,
+	/// Verifications running through this proxy.
+	in_use: AtomicUsize,
+	/// Proxy errors in a row.
+	errors: AtomicU32,
+	ejected_until: Mutex<Option<Instant>>,
+}
+
+impl PooledProxy {
+	fn is_ejected(&self, now: Instant) -> bool {
+		let ejected_until = self
+			.ejected_until
+			.lock()
+			.expect("Proxy lock poisoned. qed.");
+		ejected_until.is_some_and(|until| until > now)
+	}
+
+	fn eject(&self, duration: Duration) {
+		*self
+			.ejected_until
+			.lock()
+			.expect("Proxy lock poisoned. qed.") = Some(Instant::now() + duration);
+	}
+
+	fn readmit(&self) {
+		self.errors.store(0, Ordering::Relaxed);
+		*self
+			.ejected_until
+			.lock()
+			.expect("Proxy lock poisoned. qed.") = None;
+	}
+}
+
+/// A pool of proxies.
+#[derive(Debug)]
+pub struct ProxyPool {
+	name: String,
+	selection: ProxySelection,
+	proxies: Vec<Arc<PooledProxy>>,
+	next: AtomicUsize,
+	max_errors: u32,
+	ejection: Duration,
+}
+
+impl ProxyPool {
+	fn new(config: &ProxyPoolConfig, max_errors: u32, ejection: Duration) -> Result<Self, String> {
+		let proxies = config
+			.urls
+			.iter()
+			.map(|url| {
+				let proxy = parse_proxy_url(url)
+					.map_err(|e| format!("invalid proxy in pool {:?}: {}", config.name, e))?;
+				Ok(Arc::new(PooledProxy {
+					id: proxy_id(&proxy),
+					proxy,
+					in_use: AtomicUsize::new(0),
+					errors: AtomicU32::new(0),
+					ejected_until: Mutex::new(None),
+				}))
+			})
+			.collect::<Result<Vec<_>, String>>()?;
+		if proxies.is_empty() {
+			return Err(format!("pool {:?} has no proxies", config.name));
+		}
+
+		Ok(ProxyPool {
+			name: config.name.clone(),
+			selection: config.selection,
+			proxies,
+			next: AtomicUsize::new(0),
+			max_errors,
+			ejection,
+		})
+	}
+
+	/// Pick the proxy of a verification. If all the proxies are ejected,
+	/// they're all candidates, rather than verifying without a proxy.
+	pub fn lease(&self) -> ProxyLease {
+		let now = Instant::now();
+		let mut candidates: Vec<&Arc<PooledProxy>> =
+			self.proxies.iter().filter(|p| !p.is_ejected(now)).collect();
+		if candidates.is_empty() {
+			log::warn!(target: "reacher", "All proxies of [pool={}] are ejected", self.name);
+			candidates = self.proxies.iter().collect();
+		}
+
+		let proxy = match self.selection {
+			ProxySelection::RoundRobin => {
+				candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+			}
+			ProxySelection::LeastUsed => candidates
+				.iter()
+				.min_by_key(|p| p.in_use.load(Ordering::Relaxed))
+				.expect("Pools have at least one proxy. qed."),
+		};
+		proxy.in_use.fetch_add(1, Ordering::Relaxed);
+
+		ProxyLease {
+			proxy: proxy.clone(),
+			max_errors: self.max_errors,
+			ejection: self.ejection,
+		}
+	}
+}
+
+/// A proxy picked for a verification.
+pub struct ProxyLease {
+	proxy: Arc<PooledProxy>,
+	max_errors: u32,
+	ejection: Duration,
+}
+
+impl ProxyLease {
+	pub fn id(&self) -> &str {
+		&self.proxy.id
+	}
+
+	pub fn proxy(&self) -> &CheckEmailInputProxy {
+		&self.proxy.proxy
+	}
+
+	/// Update the health of the proxy with the outcome of the verification.
+	/// Only SOCKS errors count against the proxy, whatever the configured
+	/// error rules.
+	pub fn report(&self, output: &CheckEmailOutput) {
+		if matches!(output.smtp, Err(SmtpError::SocksError(_))) {
+			let errors = self.proxy.errors.fetch_add(1, Ordering::Relaxed) + 1;
+			if errors >= self.max_errors && !self.proxy.is_ejected(Instant::now()) {
+				log::warn!(
+					target: "reacher",
+					"Ejecting [proxy={}] after [errors={}]",
+					self.proxy.id,
+					errors
+				);
+				self.proxy.eject(self.ejection);
+			}
+		} else if output.smtp.is_ok() {
+			self.proxy.errors.store(0, Ordering::Relaxed);
+		}
+	}
+}
+
+impl Drop for ProxyLease {
+	fn drop(&mut self) {
+		self.proxy.in_use.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// The configured pools.
+#[derive(Debug, Default)]
+pub struct ProxyPools {
+	pools: HashMap<String, Arc<ProxyPool>>,
+	default_pool: Option<String>,
+	health_check_target: Option<(String, u16)>,
+	health_check_interval: Duration,
+}
+
+impl ProxyPools {
+	pub fn from_config(config: &ProxiesConfig) -> Result<Self, String> {
+		let mut pools = HashMap::new();
+		for pool in &config.pools {
+			if pool.name.is_empty() {
+				return Err("pools should have a name".into());
+			}
+			let ejection = Duration::from_secs(config.ejection_secs);
+			let pool = ProxyPool::new(pool, config.max_errors.max(1), ejection)?;
+			if pools.insert(pool.name.clone(), Arc::new(pool)).is_some() {
+				return Err("pool names should be unique".into());
+			}
+		}
+		if let Some(name) = config.default_pool.as_deref() {
+			if !pools.contains_key(name) {
+				return Err(format!("unknown default_pool {:?}", name));
+			}
+		}
+		let health_check_target = match config.health_check_target.as_deref() {
+			Some(target) => Some(
+				target
+					.rsplit_once(':')
+					.and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+					.ok_or("health_check_target should be host:port")?,
+			),
+			None => None,
+		};
+
+		Ok(ProxyPools {
+			pools,
+			default_pool: config.default_pool.clone(),
+			health_check_target,
+			health_check_interval: Duration::from_secs(config.health_check_interval_secs),
+		})
+	}
+
+	pub fn get(&self, name: &str) -> Option<&ProxyPool> {
+		self.pools.get(name).map(Arc::as_ref)
+	}
+
+	/// Pool of the verifications without a proxy, if any.
+	pub fn default_pool(&self) -> Option<&ProxyPool> {
+		self.get(self.default_pool.as_deref()?)
+	}
+
+	/// Check that each proxy can open a connection to the health check
+	/// target, ejecting the failing ones and readmitting the others.
+	pub async fn health_check(&self) {
+		let (host, port) = match &self.health_check_target {
+			Some((host, port)) => (host.clone(), *port),
+			None => return,
+		};
+
+		let checks = self
+			.pools
+			.values()
+			.flat_map(|pool| pool.proxies.iter().map(move |proxy| (pool, proxy)));
+		join_all(checks.map(|(pool, proxy)| {
+			let (host, server) = (host.clone(), proxy_id(&proxy.proxy));
+			async move {
+				let config = Socks5Config::default();
+				let connect = async {
+					match (&proxy.proxy.username, &proxy.proxy.password) {
+						(Some(username), Some(password)) => {
+							Socks5Stream::connect_with_password(
+								server,
+								host,
+								port,
+								username.clone(),
+								password.clone(),
+								config,
+							)
+							.await
+						}
+						_ => Socks5Stream::connect(server, host, port, config).await,
+					}
+				};
+				let error = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, connect).await {
+					Ok(Ok(_)) => return proxy.readmit(),
+					Ok(Err(e)) => e.to_string(),
+					Err(_) => "timed out".into(),
+				};
+
+				log::warn!(
+					target: "reacher",
+					"Health check of [proxy={}] in [pool={}] failed with [error={}]",
+					proxy.id,
+					pool.name,
+					error
+				);
+				proxy.eject(pool.ejection);
+			}
+		}))
+		.await;
+	}
+
+	/// Run the health checks forever, if enabled and there are pools.
+	pub async fn run_health_checks(&self) {
+		if self.pools.is_empty()
+			|| self.health_check_target.is_none()
+			|| self.health_check_interval.is_zero()
+		{
+			return;
+		}
+
+		let mut interval = tokio::time::interval(self.health_check_interval);
+		loop {
+			interval.tick().await;
+			self.health_check().await;
+		}
+	}
+}
+
+/// Same as [`check_email_with_timeout`], using a proxy of `pool` if the
+/// input has none. Also returns the proxy used, if any.
+pub async fn check_email_with_proxy(
+	input: &CheckEmailInput,
+	pool: Option<&ProxyPool>,
+	timeout: Option<Duration>,
+	state: &AppState,
+) -> (CheckEmailOutput, Option<String>) {
+	if let Some(proxy) = &input.proxy {
+		return (
+			check_email_with_timeout(input, timeout, state).await,
+			Some(proxy_id(proxy)),
+		);
+	}
+	let lease = match pool {
+		Some(pool) => pool.lease(),
+		None => return (check_email_with_timeout(input, timeout, state).await, None),
+	};
+
+	let mut input = input.clone();
+	input.set_proxy(lease.proxy().clone());
+	let output = check_email_with_timeout(&input, timeout, state).await;
+	lease.report(&output);
+
+	(output, Some(lease.id().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn pool(selection: ProxySelection) -> ProxyPool {
+		ProxyPool::new(
+			&ProxyPoolConfig {
+				name: "test".into(),
+				selection,
+				urls: vec![
+					"socks5://a.io:1080".into(),
+					"socks5://me:s3:cret@b.io:1080".into(),
+				],
+			},
+			3,
+			Duration::from_secs(60),
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn test_parse_proxy_url() {
+		let proxy = parse_proxy_url("socks5://me:s3:cret@my-proxy.io:1080").unwrap();
+		assert_eq!(proxy.host, "my-proxy.io");
+		assert_eq!(proxy.port, 1080);
+		assert_eq!(proxy.username.as_deref(), Some("me"));
+		assert_eq!(proxy.password.as_deref(), Some("s3:cret"));
+		assert_eq!(proxy_id(&proxy), "my-proxy.io:1080");
+
+		assert!(parse_proxy_url("http://my-proxy.io:1080").is_err());
+		assert!(parse_proxy_url("socks5://my-proxy.io").is_err());
+		assert!(parse_proxy_url("socks5://me@my-proxy.io:1080").is_err());
+	}
+
+	#[test]
+	fn test_round_robin() {
+		let pool = pool(ProxySelection::RoundRobin);
+		let ids: Vec<String> = (0..3).map(|_| pool.lease().id().to_string()).collect();
+		assert_eq!(ids, vec!["a.io:1080", "b.io:1080", "a.io:1080"]);
+
+		// Ejected proxies are skipped, unless they all are.
+		pool.proxies[0].eject(Duration::from_secs(60));
+		assert_eq!(pool.lease().id(), "b.io:1080");
+		assert_eq!(pool.lease().id(), "b.io:1080");
+		pool.proxies[1].eject(Duration::from_secs(60));
+		assert_eq!(pool.lease().id(), "b.io:1080");
+		assert_eq!(pool.lease().id(), "a.io:1080");
+		pool.proxies[0].readmit();
+		assert_eq!(pool.lease().id(), "a.io:1080");
+	}
+
+	#[test]
+	fn test_least_used() {
+		let pool = pool(ProxySelection::LeastUsed);
+		let first = pool.lease();
+		let second = pool.lease();
+		assert_ne!(first.id(), second.id());
+		drop(first);
+		assert_eq!(pool.lease().id(), "a.io:1080");
+	}
+}
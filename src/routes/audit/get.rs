@@ -0,0 +1,118 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! This file implements the `GET /v0/audit` endpoint, to read the audit log.
+
+use super::AuditAction;
+use crate::config::Config;
+use crate::routes::{
+	auth::with_admin_auth,
+	bulk::{with_db, BulkError},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use warp::Filter;
+
+/// Query parameters, all optional. Entries are returned newest first.
+#[derive(Debug, Deserialize)]
+struct AuditRequest {
+	job_id: Option<i32>,
+	action: Option<AuditAction>,
+	caller: Option<String>,
+	/// RFC 3339 date of the oldest entry.
+	since: Option<DateTime<Utc>>,
+	/// RFC 3339 date after the newest entry.
+	until: Option<DateTime<Utc>>,
+	/// Defaults to 100, at most 1000.
+	limit: Option<u64>,
+	offset: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct AuditEntryResponse {
+	id: i64,
+	created_at: DateTime<Utc>,
+	request_id: Option<String>,
+	caller: Option<String>,
+	remote_addr: Option<String>,
+	action: String,
+	method: String,
+	route: String,
+	job_id: Option<i32>,
+	status: i16,
+}
+
+#[derive(Debug, Serialize)]
+struct AuditResponseBody {
+	entries: Vec<AuditEntryResponse>,
+}
+
+/// The endpoint handler.
+async fn handler(
+	req: AuditRequest,
+	conn_pool: Pool<Postgres>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+	let entries = sqlx::query_as!(
+		AuditEntryResponse,
+		r#"
+		SELECT id, created_at, request_id, caller, remote_addr, action, method, route, job_id, status
+		FROM audit_log
+		WHERE ($1::INTEGER IS NULL OR job_id = $1)
+			AND ($2::TEXT IS NULL OR action = $2)
+			AND ($3::TEXT IS NULL OR caller = $3)
+			AND ($4::TIMESTAMPTZ IS NULL OR created_at >= $4)
+			AND ($5::TIMESTAMPTZ IS NULL OR created_at < $5)
+		ORDER BY id DESC
+		LIMIT $6 OFFSET $7
+		"#,
+		req.job_id,
+		req.action.map(|a| a.as_str()),
+		req.caller,
+		req.since,
+		req.until,
+		req.limit.unwrap_or(100).min(1000) as i64,
+		req.offset.unwrap_or(0) as i64,
+	)
+	.fetch_all(&conn_pool)
+	.await
+	.map_err(|e| {
+		log::error!(
+			target: "reacher",
+			"Failed to read the audit log with [error={}]",
+			e
+		);
+
+		BulkError::from(e)
+	})?;
+
+	Ok(warp::reply::json(&AuditResponseBody { entries }))
+}
+
+/// Create the `GET /v0/audit` endpoint. It needs the admin token, and 404s
+/// if bulk is disabled.
+pub fn get_audit_log(
+	config: Arc<Config>,
+	o: Option<Pool<Postgres>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+	warp::path!("v0" / "audit")
+		.and(warp::get())
+		.and(with_admin_auth(config))
+		.and(warp::query::<AuditRequest>())
+		.and(with_db(o))
+		.and_then(handler)
+}
@@ -0,0 +1,272 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Audit log of the API accesses and administrative actions, stored in the
+//! `audit_log` table and read with `GET /v0/audit`.
+
+pub mod get;
+
+use super::{auth::is_admin, request_id::REQUEST_ID_HEADER, route_label};
+use crate::config::Config;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::{net::SocketAddr, sync::Arc};
+use warp::{
+	http::{HeaderMap, Method},
+	hyper::body,
+	path::FullPath,
+	reply::Response,
+	Filter, Rejection,
+};
+
+/// What a request did.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+	CheckEmail,
+	CreateJob,
+	ReadJob,
+	ExportResults,
+	EraseEmail,
+	ReadAuditLog,
+}
+
+impl AuditAction {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			AuditAction::CheckEmail => "check_email",
+			AuditAction::CreateJob => "create_job",
+			AuditAction::ReadJob => "read_job",
+			AuditAction::ExportResults => "export_results",
+			AuditAction::EraseEmail => "erase_email",
+			AuditAction::ReadAuditLog => "read_audit_log",
+		}
+	}
+}
+
+/// The audited action of a request, its route, as given by [`route_label`]
+/// so that no email ends up in the log, and its job, if any. Other requests,
+/// e.g. the probes, aren't audited.
+fn audited_route(method: &Method, path: &str) -> Option<(AuditAction, String, Option<i32>)> {
+	let route = route_label(path.trim_end_matches('/'));
+	let action = match (method, route.as_str()) {
+		(&Method::POST, "/v0/check_email") => AuditAction::CheckEmail,
+		(&Method::POST, "/v0/bulk") => AuditAction::CreateJob,
+		(&Method::GET, "/v0/bulk/{id}") => AuditAction::ReadJob,
+		(&Method::GET, "/v0/bulk/{id}/results") => AuditAction::ExportResults,
+		(&Method::DELETE, "/v0/emails/{email}") => AuditAction::EraseEmail,
+		(&Method::GET, "/v0/audit") => AuditAction::ReadAuditLog,
+		_ => return None,
+	};
+	let job_id = match action {
+		AuditAction::ReadJob | AuditAction::ExportResults => {
+			path.split('/').nth(3).and_then(|id| id.parse().ok())
+		}
+		_ => None,
+	};
+
+	Some((action, route, job_id))
+}
+
+/// Who made a request: `admin` with the admin token, else the header set in
+/// `RCH_AUDIT_CALLER_HEADER`, e.g. by an authenticating gateway.
+fn caller(config: &Config, headers: &HeaderMap) -> Option<String> {
+	let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+	if is_admin(config, header("authorization")) {
+		return Some("admin".into());
+	}
+	config
+		.audit_caller_header
+		.as_deref()
+		.and_then(header)
+		.map(str::to_string)
+}
+
+/// The client address. With `RCH_TRUST_FORWARDED_FOR`, it's the one added by
+/// the reverse proxy, i.e. the last one of `X-Forwarded-For`, as the ones
+/// before may be sent by the client.
+fn remote_addr(config: &Config, headers: &HeaderMap, addr: Option<SocketAddr>) -> Option<String> {
+	let forwarded = headers
+		.get("x-forwarded-for")
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.rsplit(',').next())
+		.map(|v| v.trim().to_string())
+		.filter(|v| config.trust_forwarded_for && !v.is_empty());
+
+	forwarded.or_else(|| addr.map(|a| a.ip().to_string()))
+}
+
+/// An entry of the audit log.
+struct AuditEntry {
+	request_id: Option<String>,
+	caller: Option<String>,
+	remote_addr: Option<String>,
+	action: AuditAction,
+	method: String,
+	route: String,
+	job_id: Option<i32>,
+	status: i16,
+}
+
+async fn insert_entry(conn_pool: &Pool<Postgres>, entry: AuditEntry) {
+	let res = sqlx::query!(
+		r#"
+		INSERT INTO audit_log (request_id, caller, remote_addr, action, method, route, job_id, status)
+		VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+		"#,
+		entry.request_id,
+		entry.caller,
+		entry.remote_addr,
+		entry.action.as_str(),
+		entry.method,
+		entry.route,
+		entry.job_id,
+		entry.status,
+	)
+	.execute(conn_pool)
+	.await;
+
+	if let Err(e) = res {
+		log::error!(
+			target: "reacher",
+			"Failed to write audit log entry for [action={}] [request_id={:?}] with [error={}]",
+			entry.action.as_str(),
+			entry.request_id,
+			e
+		);
+	}
+}
+
+/// Wrap `routes` so that the audited requests are written to the audit log
+/// in the background once responded, if a database is configured. The ID of a
+/// created job is read from the response body.
+pub fn with_audit_log<F>(
+	config: Arc<Config>,
+	o: Option<Pool<Postgres>>,
+	routes: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+	F: Filter<Extract = (Response,), Error = Rejection> + Clone + Send + Sync + 'static,
+{
+	warp::method()
+		.and(warp::path::full())
+		.and(warp::header::headers_cloned())
+		.and(warp::addr::remote())
+		.and(routes)
+		.and_then(
+			move |method: Method,
+			      path: FullPath,
+			      headers: HeaderMap,
+			      addr: Option<SocketAddr>,
+			      mut response: Response| {
+				let config = config.clone();
+				let o = o.clone();
+				async move {
+					let (conn_pool, (action, route, mut job_id)) =
+						match (o, audited_route(&method, path.as_str())) {
+							(Some(conn_pool), Some(audited)) => (conn_pool, audited),
+							_ => return Ok::<_, Rejection>(response),
+						};
+
+					if action == AuditAction::CreateJob && response.status().is_success() {
+						let bytes = body::to_bytes(response.body_mut())
+							.await
+							.unwrap_or_default();
+						job_id = serde_json::from_slice::<serde_json::Value>(&bytes)
+							.ok()
+							.and_then(|v| v["job_id"].as_i64())
+							.map(|id| id as i32);
+						*response.body_mut() = bytes.into();
+					}
+
+					let entry = AuditEntry {
+						request_id: response
+							.headers()
+							.get(REQUEST_ID_HEADER)
+							.and_then(|v| v.to_str().ok())
+							.map(str::to_string),
+						caller: caller(&config, &headers),
+						remote_addr: remote_addr(&config, &headers, addr),
+						action,
+						method: method.to_string(),
+						route,
+						job_id,
+						status: response.status().as_u16() as i16,
+					};
+					// Don't hold the response until the entry is written.
+					tokio::spawn(async move { insert_entry(&conn_pool, entry).await });
+
+					Ok(response)
+				}
+			},
+		)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_audited_route() {
+		assert_eq!(
+			audited_route(&Method::GET, "/v0/bulk/42/results"),
+			Some((
+				AuditAction::ExportResults,
+				"/v0/bulk/{id}/results".into(),
+				Some(42)
+			))
+		);
+		assert_eq!(
+			audited_route(&Method::DELETE, "/v0/emails/someone@gmail.com"),
+			Some((AuditAction::EraseEmail, "/v0/emails/{email}".into(), None))
+		);
+		assert_eq!(audited_route(&Method::GET, "/v0/bulk"), None);
+		assert_eq!(audited_route(&Method::GET, "/healthz"), None);
+	}
+
+	#[test]
+	fn test_caller() {
+		let mut config = Config::default();
+		config.admin_token = Some("s3cret".into());
+		config.audit_caller_header = Some("x-user".into());
+		let mut headers = HeaderMap::new();
+		assert_eq!(caller(&config, &headers), None);
+		headers.insert("x-user", "alice".parse().unwrap());
+		assert_eq!(caller(&config, &headers), Some("alice".into()));
+		headers.insert("authorization", "Bearer s3cret".parse().unwrap());
+		assert_eq!(caller(&config, &headers), Some("admin".into()));
+	}
+
+	#[test]
+	fn test_remote_addr() {
+		let addr = Some(([10, 0, 0, 1], 443).into());
+		let mut headers = HeaderMap::new();
+		headers.insert("x-forwarded-for", "6.6.6.6, 1.2.3.4".parse().unwrap());
+
+		let mut config = Config::default();
+		assert_eq!(
+			remote_addr(&config, &headers, addr),
+			Some("10.0.0.1".into())
+		);
+		config.trust_forwarded_for = true;
+		assert_eq!(remote_addr(&config, &headers, addr), Some("1.2.3.4".into()));
+		assert_eq!(
+			remote_addr(&config, &HeaderMap::new(), addr),
+			Some("10.0.0.1".into())
+		);
+	}
+}
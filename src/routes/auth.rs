@@ -0,0 +1,83 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Authentication of the admin endpoints, with the `Authorization: Bearer`
+//! header.
+
+use crate::config::Config;
+use crate::errors::ReacherResponseError;
+use std::sync::Arc;
+use warp::{http::StatusCode, Filter};
+
+/// Compare two tokens in constant time, to not leak the configured one.
+fn tokens_match(expected: &str, given: &str) -> bool {
+	expected.len() == given.len()
+		&& expected
+			.bytes()
+			.zip(given.bytes())
+			.fold(0, |acc, (a, b)| acc | (a ^ b))
+			== 0
+}
+
+/// Whether the `Authorization` header carries the admin token.
+pub(crate) fn is_admin(config: &Config, header: Option<&str>) -> bool {
+	match (
+		&config.admin_token,
+		header.and_then(|h| h.strip_prefix("Bearer ")),
+	) {
+		(Some(expected), Some(given)) => tokens_match(expected, given),
+		_ => false,
+	}
+}
+
+/// Reject the requests without the admin token given in `RCH_ADMIN_TOKEN`.
+/// If no token is configured, the admin endpoints are disabled.
+pub(crate) fn with_admin_auth(
+	config: Arc<Config>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+	warp::header::optional::<String>("authorization")
+		.and_then(move |header: Option<String>| {
+			let result = if config.admin_token.is_none() {
+				Err(warp::reject::custom(ReacherResponseError::new(
+					StatusCode::FORBIDDEN,
+					"Admin endpoints are disabled, set RCH_ADMIN_TOKEN to enable them",
+				)))
+			} else if is_admin(&config, header.as_deref()) {
+				Ok(())
+			} else {
+				Err(warp::reject::custom(ReacherResponseError::new(
+					StatusCode::UNAUTHORIZED,
+					"Missing or invalid admin token",
+				)))
+			};
+
+			async move { result }
+		})
+		.untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::tokens_match;
+
+	#[test]
+	fn test_tokens_match() {
+		assert!(tokens_match("s3cret", "s3cret"));
+		assert!(!tokens_match("s3cret", "s3cre"));
+		assert!(!tokens_match("s3cret", "s3creT"));
+		assert!(!tokens_match("s3cret", ""));
+	}
+}
@@ -14,9 +14,142 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use sqlx::{Pool, Postgres};
+use crate::encryption::Keyring;
+use sqlx::{
+	migrate::{Migrate, MigrateError, Migrator},
+	Pool, Postgres,
+};
+use std::collections::HashSet;
 use warp::Filter;
 
+/// The database migrations, embedded in the binary.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// A migration embedded in the binary, and whether it's applied.
+#[derive(Debug)]
+pub struct MigrationStatus {
+	pub version: i64,
+	pub description: String,
+	pub applied: bool,
+}
+
+/// List the migrations embedded in the binary, oldest first.
+pub async fn migration_status(
+	conn_pool: &Pool<Postgres>,
+) -> Result<Vec<MigrationStatus>, MigrateError> {
+	let mut conn = conn_pool.acquire().await?;
+	conn.ensure_migrations_table().await?;
+	let applied: HashSet<i64> = conn
+		.list_applied_migrations()
+		.await?
+		.into_iter()
+		.map(|m| m.version)
+		.collect();
+
+	Ok(MIGRATOR
+		.iter()
+		.filter(|m| !m.migration_type.is_down_migration())
+		.map(|m| MigrationStatus {
+			version: m.version,
+			description: m.description.to_string(),
+			applied: applied.contains(&m.version),
+		})
+		.collect())
+}
+
+/// Revert the latest applied migration, and return its version, if any.
+pub async fn undo_last_migration(conn_pool: &Pool<Postgres>) -> Result<Option<i64>, MigrateError> {
+	let mut applied: Vec<i64> = migration_status(conn_pool)
+		.await?
+		.into_iter()
+		.filter(|m| m.applied)
+		.map(|m| m.version)
+		.collect();
+	let last = match applied.pop() {
+		Some(last) => last,
+		None => return Ok(None),
+	};
+
+	// Reverts all the migrations above the target.
+	MIGRATOR
+		.undo(conn_pool, applied.last().copied().unwrap_or(0))
+		.await?;
+
+	Ok(Some(last))
+}
+
+/// Rewrite the stored results encrypted with an older key, or not matching
+/// the current `encryption.mode`, and return their number. Results which
+/// can't be decrypted are logged and left as is.
+pub async fn rotate_results(
+	conn_pool: &Pool<Postgres>,
+	keyring: &Keyring,
+) -> Result<u64, sqlx::Error> {
+	let mut last_id = 0;
+	let mut count = 0;
+	loop {
+		let rows = sqlx::query!(
+			r#"
+			SELECT id, result AS "result!", raw_input FROM email_results
+			WHERE id > $1 AND result IS NOT NULL ORDER BY id LIMIT 500
+			"#,
+			last_id
+		)
+		.fetch_all(conn_pool)
+		.await?;
+		let last = match rows.last() {
+			Some(row) => row.id,
+			None => return Ok(count),
+		};
+
+		let mut tx = conn_pool.begin().await?;
+		for row in rows
+			.into_iter()
+			.filter(|row| keyring.needs_rotation(&row.result))
+		{
+			let stored = match keyring.reveal(row.result, row.raw_input) {
+				Ok((result, raw_input)) => keyring.protect(result, raw_input),
+				Err(e) => {
+					log::error!(target: "reacher", "Failed to decrypt [result={}] with [error={}]", row.id, e);
+					continue;
+				}
+			};
+			sqlx::query!(
+				r#"
+				UPDATE email_results SET result = $2, raw_input = $3, normalized_email = $4
+				WHERE id = $1
+				"#,
+				row.id,
+				stored.result,
+				stored.raw_input,
+				stored.normalized_email,
+			)
+			.execute(&mut tx)
+			.await?;
+			count += 1;
+		}
+		tx.commit().await?;
+		last_id = last;
+	}
+}
+
+/// Number of stored results whose email is hashed with each key, by key id.
+/// `DELETE /v0/emails/{email}` only finds them with their key, and hashed
+/// results can't be rotated to a newer one.
+pub async fn hash_key_usage(conn_pool: &Pool<Postgres>) -> Result<Vec<(String, i64)>, sqlx::Error> {
+	let rows = sqlx::query!(
+		r#"
+		SELECT split_part(normalized_email, ':', 2) AS "key_id!", COUNT(*) AS "count!"
+		FROM email_results WHERE normalized_email LIKE 'hmac:%'
+		GROUP BY 1 ORDER BY 1
+		"#
+	)
+	.fetch_all(conn_pool)
+	.await?;
+
+	Ok(rows.into_iter().map(|r| (r.key_id, r.count)).collect())
+}
+
 /// Warp filter that extracts a Pg Pool if the option is Some, or else rejects
 /// with a 404.
 pub fn with_db(
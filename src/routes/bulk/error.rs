@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::fmt;
 use warp::reject;
 
 #[derive(Debug)]
@@ -31,8 +32,35 @@ pub enum BulkError {
 	Db(sqlx::Error),
 	Csv(CsvError),
 	Json(serde_json::Error),
+	/// A stored result couldn't be decrypted.
+	Decrypt(String),
 }
 
+impl fmt::Display for CsvError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			CsvError::CsvLib(e) => write!(f, "{}", e),
+			CsvError::CsvLibWriter(e) => write!(f, "{}", e),
+			CsvError::Parse(e) => write!(f, "{}", e),
+		}
+	}
+}
+
+impl fmt::Display for BulkError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			BulkError::EmptyInput => write!(f, "Empty input"),
+			BulkError::JobInProgress => write!(f, "Job is still in progress"),
+			BulkError::Db(e) => write!(f, "Database error: {}", e),
+			BulkError::Csv(e) => write!(f, "Csv error: {}", e),
+			BulkError::Json(e) => write!(f, "Json error: {}", e),
+			BulkError::Decrypt(e) => write!(f, "Decryption error: {}", e),
+		}
+	}
+}
+
+impl std::error::Error for BulkError {}
+
 // Defaults to Internal server error
 impl reject::Reject for BulkError {}
 
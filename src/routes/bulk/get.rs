@@ -42,6 +42,7 @@ struct JobRecord {
 	id: i32,
 	created_at: DateTime<Utc>,
 	total_records: i32,
+	expires_at: Option<DateTime<Utc>>,
 }
 
 /// Summary of a bulk verification job status
@@ -59,6 +60,8 @@ struct JobStatusResponseBody {
 	job_id: i32,
 	created_at: DateTime<Utc>,
 	finished_at: Option<DateTime<Utc>>,
+	/// Date after which the job and its results are cleaned up, if any.
+	expires_at: Option<DateTime<Utc>>,
 	total_records: i32,
 	total_processed: i32,
 	summary: JobStatusSummary,
@@ -72,7 +75,7 @@ async fn job_status(
 	let job_rec = sqlx::query_as!(
 		JobRecord,
 		r#"
-		SELECT id, created_at, total_records FROM bulk_jobs
+		SELECT id, created_at, total_records, expires_at FROM bulk_jobs
 		WHERE id = $1
 		LIMIT 1
 		"#,
@@ -137,6 +140,7 @@ async fn job_status(
 		job_id: job_rec.id,
 		created_at: job_rec.created_at,
 		finished_at,
+		expires_at: job_rec.expires_at,
 		total_records: job_rec.total_records,
 		total_processed: agg_info
 			.total_processed
@@ -164,6 +168,4 @@ pub fn get_bulk_job_status(
 		.and(warp::get())
 		.and(with_db(o))
 		.and_then(job_status)
-		// View access logs by setting `RUST_LOG=reacher`.
-		.with(warp::log("reacher"))
 }
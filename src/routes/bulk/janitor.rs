@@ -0,0 +1,375 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Data retention for bulk jobs.
+//!
+//! Each bulk job gets an `expires_at` date when it's created, computed from
+//! its retention period. The janitor below periodically looks for expired
+//! jobs, and either deletes them along with their results, or anonymizes
+//! their results, i.e. strips everything that identifies the email owner
+//! but keeps the verification outcome for statistics.
+//!
+//! Jobs which still have tasks waiting in the queue are left alone until
+//! these tasks are done, so that a long-running job isn't cleaned up while
+//! it's still writing results.
+
+use crate::config::RedactionConfig;
+use crate::redact::{mask_strings, EmailRedaction, Redactor};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{PgConnection, Pool, Postgres};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Longest retention period of a job, in days, about a century. Longer ones
+/// would overflow the `expires_at` date.
+const MAX_RETENTION_DAYS: u32 = 36_500;
+
+/// Retention period of the bulk jobs, in days. `None` means forever.
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+	max_days: Option<u32>,
+}
+
+impl Retention {
+	pub fn new(max_days: Option<u32>) -> Self {
+		Retention { max_days }
+	}
+
+	/// Retention period of a job, in days. A job may ask for a shorter
+	/// retention than the global one, but not a longer one, nor one longer
+	/// than [`MAX_RETENTION_DAYS`].
+	pub fn days(&self, requested_days: Option<u32>) -> Option<u32> {
+		let days = match (requested_days, self.max_days) {
+			(Some(requested), Some(max)) => Some(requested.min(max)),
+			(requested, max) => requested.or(max),
+		};
+
+		days.map(|days| days.min(MAX_RETENTION_DAYS))
+	}
+}
+
+/// What to do with expired jobs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JanitorMode {
+	/// Delete the job and all its results.
+	#[default]
+	Delete,
+	/// Keep the job and its results, but remove the emails and the caller's
+	/// metadata from the results.
+	Anonymize,
+}
+
+/// A job past its `expires_at` date.
+#[derive(Clone, Copy, Debug)]
+struct ExpiredJob {
+	id: i32,
+	/// Number of the job's tasks still in the queue with attempts left, i.e.
+	/// waiting to run, running, or waiting to be retried.
+	pending_tasks: i64,
+}
+
+/// Ids of the expired jobs which can be cleaned up, i.e. which don't have
+/// pending tasks anymore.
+fn cleanable_job_ids(jobs: &[ExpiredJob]) -> Vec<i32> {
+	jobs.iter()
+		.filter(|job| job.pending_tasks == 0)
+		.map(|job| job.id)
+		.collect()
+}
+
+/// Strip everything that identifies the email owner from a stored result,
+/// keeping the verification outcome. Encrypted results only keep their
+/// fields in clear. The address, or its username, may also appear in the
+/// SMTP replies and error messages, where it's masked.
+pub fn anonymize_result(mut result: Value) -> Value {
+	let email = result["input"]
+		.as_str()
+		.unwrap_or_default()
+		.trim()
+		.to_string();
+	if let Some(obj) = result.as_object_mut() {
+		if obj.remove("encrypted").is_some() {
+			return result;
+		}
+		obj.remove("input");
+	}
+	if let Some(syntax) = result["syntax"].as_object_mut() {
+		syntax.remove("address");
+		syntax.remove("username");
+	}
+
+	let redactor = Redactor::new(&RedactionConfig {
+		emails: EmailRedaction::Mask,
+		salt: None,
+		proxy_credentials: false,
+	});
+	mask_strings(&mut result, &|text| redactor.text_about(text, &email));
+
+	result
+}
+
+/// Anonymize the results with the given ids, see [`anonymize_result`], and
+/// remove their input and the caller's metadata. Return their number.
+pub async fn anonymize_results(conn: &mut PgConnection, ids: &[i32]) -> Result<u64, sqlx::Error> {
+	let mut count = 0;
+	for ids in ids.chunks(500) {
+		let rows = sqlx::query!(
+			"SELECT id, result FROM email_results WHERE id = ANY($1)",
+			ids
+		)
+		.fetch_all(&mut *conn)
+		.await?;
+		let (ids, results): (Vec<i32>, Vec<Value>) = rows
+			.into_iter()
+			.map(|row| (row.id, row.result.map_or(Value::Null, anonymize_result)))
+			.unzip();
+
+		// One statement per chunk. A NULL result stays NULL.
+		count += sqlx::query!(
+			r#"
+			UPDATE email_results
+			SET result = NULLIF(a.result, 'null'), external_id = NULL, meta = NULL, raw_input = NULL, normalized_email = NULL
+			FROM UNNEST($1::INTEGER[], $2::JSONB[]) AS a(id, result)
+			WHERE email_results.id = a.id
+			"#,
+			&ids,
+			&results,
+		)
+		.execute(&mut *conn)
+		.await?
+		.rows_affected();
+	}
+
+	Ok(count)
+}
+
+/// Background task cleaning up expired bulk jobs.
+#[derive(Clone, Copy, Debug)]
+pub struct Janitor {
+	mode: JanitorMode,
+	interval: Duration,
+}
+
+impl Janitor {
+	/// Create a janitor running every `interval_secs`.
+	pub fn new(mode: JanitorMode, interval_secs: u64) -> Self {
+		Janitor {
+			mode,
+			interval: Duration::from_secs(interval_secs),
+		}
+	}
+
+	/// Run the janitor in the background, forever.
+	pub fn spawn(self, conn_pool: Pool<Postgres>) -> JoinHandle<()> {
+		tokio::spawn(async move {
+			loop {
+				match self.run_once(&conn_pool).await {
+					Ok(0) => {}
+					Ok(count) => log::info!(
+						target: "reacher",
+						"Cleaned up [count={}] expired bulk jobs with [mode={:?}]",
+						count,
+						self.mode
+					),
+					Err(e) => log::error!(
+						target: "reacher",
+						"Failed to clean up expired bulk jobs with [error={}]",
+						e
+					),
+				}
+
+				tokio::time::sleep(self.interval).await;
+			}
+		})
+	}
+
+	/// Clean up all the jobs expired by now, and return their number. Each
+	/// job is cleaned up in its own transaction, so that the locks are only
+	/// held for one job at a time.
+	///
+	/// A task on its last attempt isn't counted as pending, as sqlxmq doesn't
+	/// tell it apart from a task which failed for good. Its result may then
+	/// be lost if the janitor runs while it's being verified.
+	async fn run_once(&self, conn_pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+		let jobs = sqlx::query_as!(
+			ExpiredJob,
+			r#"
+			SELECT id, (
+				SELECT COUNT(*) FROM mq_payloads JOIN mq_msgs USING (id)
+				WHERE (mq_payloads.payload_json ->> 'id')::INTEGER = bulk_jobs.id
+				AND mq_msgs.attempts > 0
+			) AS "pending_tasks!"
+			FROM bulk_jobs
+			WHERE expires_at <= NOW() AND ($1 OR anonymized_at IS NULL)
+			"#,
+			self.mode == JanitorMode::Delete
+		)
+		.fetch_all(conn_pool)
+		.await?;
+
+		let mut count = 0;
+		for id in cleanable_job_ids(&jobs) {
+			if self.clean_up_job(conn_pool, id).await? {
+				count += 1;
+			}
+		}
+
+		Ok(count)
+	}
+
+	/// Clean up an expired job. Return `false` if it was already cleaned up,
+	/// e.g. by the janitor of another worker.
+	async fn clean_up_job(&self, conn_pool: &Pool<Postgres>, id: i32) -> Result<bool, sqlx::Error> {
+		let mut tx = conn_pool.begin().await?;
+
+		let locked = sqlx::query_scalar!(
+			r#"
+			SELECT id FROM bulk_jobs
+			WHERE id = $1 AND ($2 OR anonymized_at IS NULL)
+			FOR UPDATE SKIP LOCKED
+			"#,
+			id,
+			self.mode == JanitorMode::Delete
+		)
+		.fetch_optional(&mut tx)
+		.await?;
+		if locked.is_none() {
+			return Ok(false);
+		}
+		let ids = &[id][..];
+
+		match self.mode {
+			JanitorMode::Delete => {
+				// Remove the tasks left in the queue, which failed for good.
+				sqlx::query!(
+					r#"
+					DELETE FROM mq_msgs WHERE id IN (
+						SELECT id FROM mq_payloads
+						WHERE (payload_json ->> 'id')::INTEGER = ANY($1)
+					)
+					"#,
+					ids
+				)
+				.execute(&mut tx)
+				.await?;
+				sqlx::query!(
+					r#"
+					DELETE FROM mq_payloads
+					WHERE (payload_json ->> 'id')::INTEGER = ANY($1)
+					"#,
+					ids
+				)
+				.execute(&mut tx)
+				.await?;
+
+				sqlx::query!(r#"DELETE FROM email_results WHERE job_id = ANY($1)"#, ids)
+					.execute(&mut tx)
+					.await?;
+
+				sqlx::query!(r#"DELETE FROM bulk_jobs WHERE id = ANY($1)"#, ids)
+					.execute(&mut tx)
+					.await?;
+			}
+			JanitorMode::Anonymize => {
+				let result_ids =
+					sqlx::query_scalar!("SELECT id FROM email_results WHERE job_id = ANY($1)", ids)
+						.fetch_all(&mut tx)
+						.await?;
+				anonymize_results(&mut tx, &result_ids).await?;
+
+				sqlx::query!(
+					r#"UPDATE bulk_jobs SET anonymized_at = NOW() WHERE id = ANY($1)"#,
+					ids
+				)
+				.execute(&mut tx)
+				.await?;
+			}
+		};
+
+		tx.commit().await?;
+
+		Ok(true)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn test_retention_days() {
+		let retention = Retention { max_days: None };
+		assert_eq!(retention.days(None), None);
+		assert_eq!(retention.days(Some(7)), Some(7));
+		assert_eq!(retention.days(Some(u32::MAX)), Some(MAX_RETENTION_DAYS));
+
+		let retention = Retention { max_days: Some(30) };
+		assert_eq!(retention.days(None), Some(30));
+		assert_eq!(retention.days(Some(7)), Some(7));
+		assert_eq!(retention.days(Some(90)), Some(30));
+	}
+
+	#[test]
+	fn test_cleanable_job_ids() {
+		let jobs = [
+			ExpiredJob {
+				id: 1,
+				pending_tasks: 0,
+			},
+			// Still running, or waiting for greylisting retries.
+			ExpiredJob {
+				id: 2,
+				pending_tasks: 3,
+			},
+			ExpiredJob {
+				id: 3,
+				pending_tasks: 0,
+			},
+		];
+
+		assert_eq!(cleanable_job_ids(&jobs), vec![1, 3]);
+		assert!(cleanable_job_ids(&[]).is_empty());
+	}
+
+	#[test]
+	fn test_anonymize_result() {
+		let result = json!({
+			"input": "Someone@Gmail.com",
+			"is_reachable": "invalid",
+			"syntax": { "address": "Someone@Gmail.com", "username": "Someone", "domain": "gmail.com" },
+			"smtp": { "error": { "message": "550 someone@gmail.com unknown, Someone not found" } },
+			"misc": { "error": { "message": "<Someone@Gmail.com>: no such user" } }
+		});
+		assert_eq!(
+			anonymize_result(result),
+			json!({
+				"is_reachable": "invalid",
+				"syntax": { "domain": "gmail.com" },
+				"smtp": { "error": { "message": "550 ***@gmail.com unknown, *** not found" } },
+				"misc": { "error": { "message": "<***@Gmail.com>: no such user" } }
+			})
+		);
+
+		let encrypted = json!({ "is_reachable": "safe", "encrypted": "enc:v1:1:AAAA" });
+		assert_eq!(
+			anonymize_result(encrypted),
+			json!({ "is_reachable": "safe" })
+		);
+	}
+}
@@ -17,8 +17,24 @@
 mod db;
 mod error;
 pub mod get;
+mod janitor;
 pub mod post;
 pub mod results;
+mod runners;
 mod task;
+mod throttle;
+mod tracker;
 
-pub use task::email_verification_task;
+pub use db::{
+	hash_key_usage, migration_status, rotate_results, undo_last_migration, with_db,
+	MigrationStatus, MIGRATOR,
+};
+pub use error::BulkError;
+pub use janitor::{anonymize_results, Janitor, JanitorMode, Retention};
+pub use runners::JobRunners;
+pub use task::{
+	check_task_input, email_verification_task, task_result, GreylistingRetry, JobPriority,
+	SmtpPort, SmtpPortFallback, TaskInput, TaskOutput,
+};
+pub use throttle::DomainThrottle;
+pub use tracker::TaskTracker;
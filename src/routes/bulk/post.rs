@@ -19,12 +19,16 @@
 use super::{
 	db::with_db,
 	error::BulkError,
-	task::{submit_job, TaskInput},
+	task::{submit_job, JobPriority, SmtpPort, SmtpPortFallback, TaskInput},
 };
+use crate::check::SmtpLimits;
+use crate::config::Config;
+use crate::routes::with_config;
 use check_if_email_exists::CheckEmailInputProxy;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
 use sqlx::{Pool, Postgres};
-use std::cmp::min;
+use std::{cmp::min, sync::Arc};
 use warp::Filter;
 
 // this configures the number of emails passed to every task
@@ -35,53 +39,178 @@ use warp::Filter;
 // outputs and commit them to the database.
 const EMAIL_TASK_BATCH_SIZE: usize = 1;
 
+/// One item of the bulk input: either a bare email, or an object carrying
+/// the caller's own metadata, which is returned as-is alongside the result.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum BulkInputItem {
+	Email(String),
+	WithMetadata {
+		email: String,
+		#[serde(
+			default,
+			deserialize_with = "deserialize_external_id",
+			skip_serializing_if = "Option::is_none"
+		)]
+		external_id: Option<String>,
+		#[serde(skip_serializing_if = "Option::is_none")]
+		meta: Option<serde_json::Value>,
+	},
+}
+
+/// Accept the `external_id` either as a string or as a number, and store it as
+/// text in both cases.
+fn deserialize_external_id<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum ExternalId {
+		Text(String),
+		Number(serde_json::Number),
+	}
+
+	Ok(
+		Option::<ExternalId>::deserialize(deserializer)?.map(|id| match id {
+			ExternalId::Text(text) => text,
+			ExternalId::Number(number) => number.to_string(),
+		}),
+	)
+}
+
+impl BulkInputItem {
+	fn email(&self) -> &str {
+		match self {
+			BulkInputItem::Email(email) | BulkInputItem::WithMetadata { email, .. } => email,
+		}
+	}
+
+	fn external_id(&self) -> Option<&String> {
+		match self {
+			BulkInputItem::Email(_) => None,
+			BulkInputItem::WithMetadata { external_id, .. } => external_id.as_ref(),
+		}
+	}
+
+	fn meta(&self) -> Option<&serde_json::Value> {
+		match self {
+			BulkInputItem::Email(_) => None,
+			BulkInputItem::WithMetadata { meta, .. } => meta.as_ref(),
+		}
+	}
+}
+
+/// One row of the bulk input, along with the JSON it was parsed from, which
+/// is stored untouched as the row's raw input.
+#[derive(Clone, Debug)]
+struct BulkInputRow {
+	item: BulkInputItem,
+	raw: Box<RawValue>,
+}
+
+impl<'de> Deserialize<'de> for BulkInputRow {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		let raw = Box::<RawValue>::deserialize(deserializer)?;
+		let item = serde_json::from_str(raw.get()).map_err(serde::de::Error::custom)?;
+
+		Ok(BulkInputRow { item, raw })
+	}
+}
+
+impl Serialize for BulkInputRow {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		self.raw.serialize(serializer)
+	}
+}
+
 /// Endpoint request body.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct CreateBulkRequestBody {
 	input_type: String,
-	input: Vec<String>,
+	input: Vec<BulkInputRow>,
 	proxy: Option<CheckEmailInputProxy>,
 	hello_name: Option<String>,
 	from_email: Option<String>,
-	smtp_ports: Option<Vec<u16>>,
+	smtp_ports: Option<Vec<SmtpPort>>,
+	smtp_port_fallback: Option<SmtpPortFallback>,
+	smtp_timeout: Option<u64>,
+	retries: Option<usize>,
+	deadline: Option<u64>,
+	/// Number of days after which the job and its results are cleaned up.
+	/// It can't be longer than the server's retention period.
+	retention_days: Option<u32>,
+	/// Use `high` for small urgent jobs, which shouldn't wait for large jobs
+	/// to finish.
+	priority: Option<JobPriority>,
 }
 
 struct CreateBulkRequestBodyIterator {
 	body: CreateBulkRequestBody,
 	index: usize,
 	batch_size: usize,
+	limits: SmtpLimits,
 }
 
-impl IntoIterator for CreateBulkRequestBody {
-	type Item = TaskInput;
-	type IntoIter = CreateBulkRequestBodyIterator;
+/// A task's input, along with the position of its email in the request's
+/// input and the raw input row.
+type TaskInputRow = (i32, String, TaskInput);
 
-	fn into_iter(self) -> Self::IntoIter {
+impl CreateBulkRequestBody {
+	/// Split the request into tasks, with SMTP options bounded by `limits`.
+	fn into_tasks(self, limits: SmtpLimits) -> CreateBulkRequestBodyIterator {
 		CreateBulkRequestBodyIterator {
 			body: self,
 			index: 0,
 			batch_size: EMAIL_TASK_BATCH_SIZE,
+			limits,
 		}
 	}
 }
 
 impl Iterator for CreateBulkRequestBodyIterator {
-	type Item = TaskInput;
+	type Item = TaskInputRow;
 
 	fn next(&mut self) -> Option<Self::Item> {
 		if self.index < self.body.input.len() {
 			let bounded_index = min(self.index + self.batch_size, self.body.input.len());
-			let to_emails = self.body.input[self.index..bounded_index].to_vec();
+			let items = &self.body.input[self.index..bounded_index];
+			let to_emails = items.iter().map(|i| i.item.email().to_string()).collect();
 			let item = TaskInput {
 				to_emails,
-				smtp_ports: self.body.smtp_ports.clone().unwrap_or_else(|| vec![25]),
+				// Tasks only have one email, see `EMAIL_TASK_BATCH_SIZE`.
+				external_id: items[0].item.external_id().cloned(),
+				meta: items[0].item.meta().cloned(),
+				smtp_ports: self
+					.body
+					.smtp_ports
+					.clone()
+					.unwrap_or_else(|| vec![SmtpPort::Port(25)]),
+				smtp_port_fallback: self.body.smtp_port_fallback.unwrap_or_default(),
 				proxy: self.body.proxy.clone(),
 				hello_name: self.body.hello_name.clone(),
 				from_email: self.body.from_email.clone(),
+				smtp_timeout: Some(self.limits.smtp_timeout(self.body.smtp_timeout)),
+				retries: Some(self.limits.retries(self.body.retries)),
+				deadline: self.limits.deadline(self.body.deadline),
+			};
+
+			let row_index = self.index as i32;
+			// Bare emails are kept as-is, objects are stored as the caller
+			// sent them.
+			let raw_input = match &items[0].item {
+				BulkInputItem::Email(email) => email.clone(),
+				BulkInputItem::WithMetadata { .. } => items[0].raw.get().to_string(),
 			};
 
 			self.index = bounded_index;
-			Some(item)
+			Some((row_index, raw_input, item))
 		} else {
 			None
 		}
@@ -95,37 +224,56 @@ struct CreateBulkResponseBody {
 }
 
 /// handles input, creates db entry for job and tasks for verification
+#[tracing::instrument(skip_all, fields(job_id))]
 async fn create_bulk_request(
+	config: Arc<Config>,
 	conn_pool: Pool<Postgres>,
-	body: CreateBulkRequestBody,
+	mut body: CreateBulkRequestBody,
 ) -> Result<impl warp::Reply, warp::Rejection> {
 	if body.input.is_empty() {
 		return Err(BulkError::EmptyInput.into());
 	}
 
+	if body.smtp_port_fallback.is_none() {
+		body.smtp_port_fallback = Some(config.bulk.smtp_port_fallback);
+	}
+
+	let retention_days = config
+		.bulk
+		.retention()
+		.days(body.retention_days)
+		.map(|days| days as i32); // Bounded by `MAX_RETENTION_DAYS`.
+
 	// create job entry
 	let rec = sqlx::query!(
 		r#"
-		INSERT INTO bulk_jobs (total_records)
-		VALUES ($1)
+		INSERT INTO bulk_jobs (total_records, expires_at)
+		VALUES ($1, NOW() + make_interval(days => $2))
 		RETURNING id
 		"#,
-		body.input.len() as i32
+		body.input.len() as i32,
+		retention_days
 	)
 	.fetch_one(&conn_pool)
 	.await
 	.map_err(|e| {
 		log::error!(
 			target: "reacher",
-			"Failed to create job record for [body={:?}] with [error={}]",
-			&body,
+			"Failed to create job record for [count={}] emails with [error={}]",
+			body.input.len(),
 			e
 		);
 		BulkError::from(e)
 	})?;
 
-	for task_input in body.into_iter() {
-		let task_uuid = submit_job(&conn_pool, rec.id, task_input).await?;
+	let priority = body.priority.unwrap_or_default();
+	tracing::Span::current().record("job_id", rec.id);
+
+	for (row_index, raw_input, task_input) in body.into_tasks(config.smtp) {
+		let task_uuid = submit_job(
+			&conn_pool, rec.id, priority, row_index, raw_input, task_input,
+		)
+		.await?;
 
 		log::debug!(
 			target: "reacher",
@@ -144,10 +292,12 @@ async fn create_bulk_request(
 /// The endpoint accepts list of email address and creates
 /// a new job to check them.
 pub fn create_bulk_job(
+	config: Arc<Config>,
 	o: Option<Pool<Postgres>>,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
 	warp::path!("v0" / "bulk")
 		.and(warp::post())
+		.and(with_config(config))
 		.and(with_db(o))
 		// When accepting a body, we want a JSON body (and to reject huge
 		// payloads)...
@@ -155,6 +305,50 @@ pub fn create_bulk_job(
 		.and(warp::body::content_length_limit(1024 * 16))
 		.and(warp::body::json())
 		.and_then(create_bulk_request)
-		// View access logs by setting `RUST_LOG=reacher`.
-		.with(warp::log("reacher"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_bulk_input_item_deserialize() {
+		let items: Vec<BulkInputItem> = serde_json::from_str(
+			r#"["foo@bar.baz", {"email": "bar@baz.qux", "external_id": "42", "meta": {"a": 1}}, {"email": "baz@qux.quux", "external_id": 7}, {"email": "qux@quux.corge"}]"#,
+		)
+		.unwrap();
+
+		assert_eq!(items[0].email(), "foo@bar.baz");
+		assert_eq!(items[0].external_id(), None);
+		assert_eq!(items[1].email(), "bar@baz.qux");
+		assert_eq!(items[1].external_id().map(String::as_str), Some("42"));
+		assert_eq!(items[1].meta(), Some(&serde_json::json!({"a": 1})));
+		assert_eq!(items[2].external_id().map(String::as_str), Some("7"));
+		assert_eq!(items[2].meta(), None);
+		assert_eq!(items[3].email(), "qux@quux.corge");
+		assert_eq!(items[3].external_id(), None);
+	}
+
+	#[test]
+	fn test_raw_input() {
+		let body: CreateBulkRequestBody = serde_json::from_str(
+			r#"{"input_type": "array", "input": [" foo@bar.baz", {"external_id": 7, "email": "bar@baz.qux", "source": "crm"}]}"#,
+		)
+		.unwrap();
+
+		let raw_inputs: Vec<_> = body
+			.into_tasks(SmtpLimits::default())
+			.map(|(row_index, raw_input, _)| (row_index, raw_input))
+			.collect();
+		assert_eq!(
+			raw_inputs,
+			vec![
+				(0, " foo@bar.baz".into()),
+				(
+					1,
+					r#"{"external_id": 7, "email": "bar@baz.qux", "source": "crm"}"#.into()
+				)
+			]
+		);
+	}
 }
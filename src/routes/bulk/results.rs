@@ -20,10 +20,14 @@ use super::{
 	db::with_db,
 	error::{BulkError, CsvError},
 };
+use crate::encryption::Keyring;
+use crate::routes::with_state;
+use crate::state::AppState;
 use csv::WriterBuilder;
 use serde::{Deserialize, Serialize};
-use sqlx::{Executor, Pool, Postgres, Row};
+use sqlx::{postgres::PgRow, Executor, Pool, Postgres, Row};
 use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
 use warp::Filter;
 
 /// Defines the download format, passed in as a query param.
@@ -52,12 +56,12 @@ struct JobResultJsonResponse {
 /// Wrapper for serde json value to convert
 /// into a csv response
 #[derive(Debug)]
-struct CsvWrapper(serde_json::Value);
+pub(crate) struct CsvWrapper(pub(crate) serde_json::Value);
 
 /// Simplified output of `CheckEmailOutput` struct
 /// for csv fields.
 #[derive(Debug, Serialize)]
-struct JobResultCsvResponse {
+pub(crate) struct JobResultCsvResponse {
 	input: String,
 	is_reachable: String,
 	#[serde(rename = "misc.is_disposable")]
@@ -83,6 +87,11 @@ struct JobResultCsvResponse {
 	#[serde(rename = "syntax.username")]
 	syntax_username: String,
 	error: Option<String>,
+	error_category: Option<String>,
+	external_id: Option<String>,
+	meta: Option<String>,
+	row_index: Option<i64>,
+	raw_input: Option<String>,
 }
 
 /// Convert csv wrapper to csv response
@@ -106,6 +115,11 @@ impl TryFrom<CsvWrapper> for JobResultCsvResponse {
 		let mut syntax_domain: String = String::default();
 		let mut syntax_username: String = String::default();
 		let mut error: Option<String> = None;
+		let mut error_category: Option<String> = None;
+		let mut external_id: Option<String> = None;
+		let mut meta: Option<String> = None;
+		let mut row_index: Option<i64> = None;
+		let mut raw_input: Option<String> = None;
 
 		let top_level = value
 			.0
@@ -203,6 +217,32 @@ impl TryFrom<CsvWrapper> for JobResultCsvResponse {
 						}
 					}
 				}
+				"error_category" => {
+					error_category = Some(
+						val.as_str()
+							.ok_or("error_category should be a string")?
+							.to_string(),
+					)
+				}
+				"external_id" => {
+					external_id = Some(
+						val.as_str()
+							.ok_or("external_id should be a string")?
+							.to_string(),
+					)
+				}
+				// Metadata is free-form, so it's written as JSON in the cell.
+				"meta" => meta = Some(val.to_string()),
+				"row_index" => {
+					row_index = Some(val.as_i64().ok_or("row_index should be an integer")?)
+				}
+				"raw_input" => {
+					raw_input = Some(
+						val.as_str()
+							.ok_or("raw_input should be a string")?
+							.to_string(),
+					)
+				}
 				// ignore unknown fields
 				_ => {}
 			}
@@ -223,14 +263,46 @@ impl TryFrom<CsvWrapper> for JobResultCsvResponse {
 			syntax_is_valid_syntax,
 			syntax_username,
 			error,
+			error_category,
+			external_id,
+			meta,
+			row_index,
+			raw_input,
 		})
 	}
 }
 
+/// Decrypt a stored result if needed, and add the caller's metadata and
+/// input position stored alongside it to the result object.
+fn result_with_metadata(keyring: &Keyring, row: &PgRow) -> Result<serde_json::Value, BulkError> {
+	let external_id: Option<String> = row.get("external_id");
+	let meta: Option<serde_json::Value> = row.get("meta");
+	let row_index: Option<i32> = row.get("row_index");
+	let (mut result, raw_input) = keyring
+		.reveal(row.get("result"), row.get("raw_input"))
+		.map_err(BulkError::Decrypt)?;
+
+	if let Some(external_id) = external_id {
+		result["external_id"] = external_id.into();
+	}
+	if let Some(meta) = meta {
+		result["meta"] = meta;
+	}
+	if let Some(row_index) = row_index {
+		result["row_index"] = row_index.into();
+	}
+	if let Some(raw_input) = raw_input {
+		result["raw_input"] = raw_input.into();
+	}
+
+	Ok(result)
+}
+
 async fn job_result(
 	job_id: i32,
 	conn_pool: Pool<Postgres>,
 	req: JobResultRequest,
+	state: Arc<AppState>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
 	// Throw an error if the job is still running.
 	// Is there a way to combine these 2 requests in one?
@@ -280,6 +352,7 @@ async fn job_result(
 				req.limit.unwrap_or(50),
 				req.offset.unwrap_or(0),
 				conn_pool,
+				&state.keyring,
 			)
 			.await?;
 
@@ -307,6 +380,7 @@ async fn job_result(
 				req.limit.unwrap_or(5000),
 				req.offset.unwrap_or(0),
 				conn_pool,
+				&state.keyring,
 			)
 			.await?;
 
@@ -320,12 +394,13 @@ async fn job_result_json(
 	limit: u64,
 	offset: u64,
 	conn_pool: Pool<Postgres>,
+	keyring: &Keyring,
 ) -> Result<Vec<serde_json::Value>, warp::Rejection> {
 	let query = sqlx::query!(
 		r#"
-		SELECT result FROM email_results
+		SELECT result, external_id, meta, row_index, raw_input FROM email_results
 		WHERE job_id = $1
-		ORDER BY id
+		ORDER BY row_index NULLS LAST, id
 		LIMIT $2 OFFSET $3
 		"#,
 		job_id,
@@ -333,7 +408,7 @@ async fn job_result_json(
 		offset as i64
 	);
 
-	let rows: Vec<serde_json::Value> = conn_pool
+	let rows = conn_pool
 		.fetch_all(query)
 		.await
 		.map_err(|e| {
@@ -349,8 +424,18 @@ async fn job_result_json(
 			BulkError::from(e)
 		})?
 		.iter()
-		.map(|row| row.get("result"))
-		.collect();
+		.map(|row| result_with_metadata(keyring, row))
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|e| {
+			log::error!(
+				target: "reacher",
+				"Failed to read results for [job={}] with [error={}]",
+				job_id,
+				e
+			);
+
+			e
+		})?;
 
 	Ok(rows)
 }
@@ -360,12 +445,13 @@ async fn job_result_csv(
 	limit: u64,
 	offset: u64,
 	conn_pool: Pool<Postgres>,
+	keyring: &Keyring,
 ) -> Result<Vec<u8>, warp::Rejection> {
 	let query = sqlx::query!(
 		r#"
-		SELECT result FROM email_results
+		SELECT result, external_id, meta, row_index, raw_input FROM email_results
 		WHERE job_id = $1
-		ORDER BY id
+		ORDER BY row_index NULLS LAST, id
 		LIMIT $2 OFFSET $3
 		"#,
 		job_id,
@@ -375,7 +461,7 @@ async fn job_result_csv(
 
 	let mut wtr = WriterBuilder::new().has_headers(true).from_writer(vec![]);
 
-	for json_value in conn_pool
+	for row in conn_pool
 		.fetch_all(query)
 		.await
 		.map_err(|e| {
@@ -389,8 +475,17 @@ async fn job_result_csv(
 			BulkError::from(e)
 		})?
 		.iter()
-		.map(|row| row.get("result"))
 	{
+		let json_value = result_with_metadata(keyring, row).map_err(|e| {
+			log::error!(
+				target: "reacher",
+				"Failed to read results for [job={}] with [error={}]",
+				job_id,
+				e
+			);
+
+			e
+		})?;
 		let result_csv: JobResultCsvResponse = CsvWrapper(json_value).try_into().map_err(|e: &'static str| {
 			log::error!(
 				target: "reacher",
@@ -434,13 +529,13 @@ async fn job_result_csv(
 }
 
 pub fn get_bulk_job_result(
+	state: Arc<AppState>,
 	o: Option<Pool<Postgres>>,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
 	warp::path!("v0" / "bulk" / i32 / "results")
 		.and(warp::get())
 		.and(with_db(o))
 		.and(warp::query::<JobResultRequest>())
+		.and(with_state(state))
 		.and_then(job_result)
-		// View access logs by setting `RUST_LOG=reacher`.
-		.with(warp::log("reacher"))
 }
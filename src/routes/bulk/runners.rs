@@ -0,0 +1,58 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Handles on the sqlxmq job runners of this process.
+
+use sqlxmq::OwnedHandle;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// The main loops of the job runners started by this process, shared between
+/// `main` (which stops them on shutdown) and the `/readyz` endpoint (which
+/// checks that they are still running).
+pub struct JobRunners {
+	/// A handle is set to `None` once stopped.
+	handles: Mutex<Vec<Option<JoinHandle<()>>>>,
+}
+
+impl JobRunners {
+	pub fn new(handles: Vec<OwnedHandle>) -> Self {
+		JobRunners {
+			handles: Mutex::new(handles.into_iter().map(|h| Some(h.into_inner())).collect()),
+		}
+	}
+
+	/// Check that the main loops of all the runners are still running. They
+	/// only exit on panic, or when stopped.
+	pub async fn is_alive(&self) -> bool {
+		let handles = self.handles.lock().await;
+
+		!handles.is_empty()
+			&& handles
+				.iter()
+				.all(|h| h.as_ref().is_some_and(|h| !h.is_finished()))
+	}
+
+	/// Stop fetching new tasks. Tasks already running are not cancelled.
+	pub async fn stop(&self) {
+		for handle in self.handles.lock().await.iter_mut() {
+			if let Some(h) = handle.take() {
+				h.abort();
+				let _ = h.await;
+			}
+		}
+	}
+}
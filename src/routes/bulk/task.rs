@@ -16,23 +16,192 @@
 
 //! This file implements the `POST /bulk` endpoint.
 
-use super::error::BulkError;
-use crate::check::{check_email, SMTP_TIMEOUT};
-use check_if_email_exists::{CheckEmailInput, CheckEmailInputProxy, CheckEmailOutput, Reachable};
+use super::{error::BulkError, throttle::DomainThrottle, tracker::TaskTracker};
+use crate::check::{SmtpLimits, SMTP_RETRIES, SMTP_TIMEOUT};
+use crate::classify::ErrorClassifier;
+use crate::proxy::check_email_with_proxy;
+use crate::routing::{proxy_pool_for, RoutingRule};
+use crate::state::AppState;
+use crate::telemetry::{extract_context, inject_context};
+use async_smtp::smtp::error::Error as AsyncSmtpError;
+use check_if_email_exists::{
+	smtp::SmtpError, CheckEmailInput, CheckEmailInputProxy, CheckEmailOutput, Reachable,
+	SmtpSecurity,
+};
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 use sqlxmq::{job, CurrentJob};
-use std::{error::Error, time::Duration};
+use std::{
+	collections::HashMap,
+	error::Error,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tracing::{Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
 
+/// TLS mode to use on a SMTP port.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpPortSecurity {
+	/// Plain text connection, for testing purposes.
+	None,
+	/// Use `STARTTLS` if the server supports it.
+	Opportunistic,
+	/// Require `STARTTLS`.
+	Starttls,
+	/// TLS from the start of the connection, usually on port 465.
+	ImplicitTls,
+}
+
+impl From<SmtpPortSecurity> for SmtpSecurity {
+	fn from(security: SmtpPortSecurity) -> Self {
+		match security {
+			SmtpPortSecurity::None => SmtpSecurity::None,
+			SmtpPortSecurity::Opportunistic => SmtpSecurity::Opportunistic,
+			SmtpPortSecurity::Starttls => SmtpSecurity::Required,
+			SmtpPortSecurity::ImplicitTls => SmtpSecurity::Wrapper,
+		}
+	}
+}
+
+/// A SMTP port to verify an email on. It's either a bare port number, in
+/// which case the TLS mode is deduced from the port, or an object with an
+/// explicit TLS mode, e.g. `{ "port": 587, "security": "starttls" }`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum SmtpPort {
+	Port(u16),
+	WithSecurity {
+		port: u16,
+		security: SmtpPortSecurity,
+	},
+}
+
+impl SmtpPort {
+	pub fn port(&self) -> u16 {
+		match self {
+			SmtpPort::Port(port) | SmtpPort::WithSecurity { port, .. } => *port,
+		}
+	}
+
+	pub fn security(&self) -> SmtpPortSecurity {
+		match self {
+			SmtpPort::WithSecurity { security, .. } => *security,
+			// Port 465 is SMTPS, i.e. implicit TLS. All other ports usually
+			// upgrade the connection with `STARTTLS`.
+			SmtpPort::Port(465) => SmtpPortSecurity::ImplicitTls,
+			SmtpPort::Port(_) => SmtpPortSecurity::Opportunistic,
+		}
+	}
+}
+
+/// Priority of a bulk job. High priority tasks are spawned on their own
+/// channel, which has dedicated runners on top of the default ones, so that
+/// small urgent jobs don't wait behind large ones.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobPriority {
+	#[default]
+	Normal,
+	High,
+}
+
+impl JobPriority {
+	/// Name of the sqlxmq channel the tasks are spawned on.
+	pub fn channel_name(&self) -> &'static str {
+		match self {
+			// sqlxmq's default channel, where older tasks live.
+			JobPriority::Normal => "",
+			JobPriority::High => "high_priority",
+		}
+	}
+}
+
+/// When to move on to the next SMTP port in `smtp_ports`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpPortFallback {
+	/// Only if we couldn't talk to the SMTP server on the current port
+	/// (connection refused, timeout, TLS or proxy error...). A definitive
+	/// answer from the server, like a 5xx rejection, is kept.
+	#[default]
+	OnConnectionError,
+	/// On any `unknown` result.
+	OnUnknown,
+	/// Only use the first port.
+	Never,
+}
+
+impl SmtpPortFallback {
+	/// Decide if the given result should be retried on the next port.
+	pub fn should_fall_back(&self, response: &CheckEmailOutput) -> bool {
+		if response.is_reachable != Reachable::Unknown {
+			return false;
+		}
+
+		match self {
+			SmtpPortFallback::OnConnectionError => has_smtp_connection_error(response),
+			SmtpPortFallback::OnUnknown => true,
+			SmtpPortFallback::Never => false,
+		}
+	}
+}
+
+/// Check if the SMTP step failed before the server could give us an answer.
+fn has_smtp_connection_error(response: &CheckEmailOutput) -> bool {
+	match &response.smtp {
+		Err(SmtpError::SocksError(_)) | Err(SmtpError::TimeoutError(_)) => true,
+		// 4xx and 5xx are answers from the server, all other errors happen
+		// at the connection level.
+		Err(SmtpError::SmtpError(e)) => !matches!(
+			e,
+			AsyncSmtpError::Transient(_) | AsyncSmtpError::Permanent(_)
+		),
+		_ => false,
+	}
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TaskInput {
 	// fields for CheckEmailInput
 	pub to_emails: Vec<String>, // chunk of email from request. This always has at most `EMAIL_TASK_BATCH_SIZE` items.
-	pub smtp_ports: Vec<u16>,   // override empty smtp ports from request with default value
+	pub smtp_ports: Vec<SmtpPort>, // override empty smtp ports from request with default value
+	#[serde(default)]
+	pub smtp_port_fallback: SmtpPortFallback,
 	pub proxy: Option<CheckEmailInputProxy>,
 	pub hello_name: Option<String>,
 	pub from_email: Option<String>,
+	pub smtp_timeout: Option<u64>, // in seconds, already bounded by the server's `SmtpLimits`
+	pub retries: Option<usize>,
+	pub deadline: Option<u64>, // total time budget for all ports, in seconds
+	// caller's metadata, stored untouched next to the result
+	pub external_id: Option<String>,
+	pub meta: Option<serde_json::Value>,
+}
+
+impl TaskInput {
+	/// Override the options of the task with the ones of a routing rule. Its
+	/// SMTP timeout is still bounded by `limits`.
+	pub fn apply_rule(&mut self, rule: &RoutingRule, limits: &SmtpLimits) {
+		if let Some(ports) = &rule.smtp_ports {
+			self.smtp_ports = ports.iter().copied().map(SmtpPort::Port).collect();
+		}
+		// The proxy then comes from the rule's pool.
+		if rule.proxy_pool.is_some() {
+			self.proxy = None;
+		}
+		if rule.hello_name.is_some() {
+			self.hello_name = rule.hello_name.clone();
+		}
+		if rule.from_email.is_some() {
+			self.from_email = rule.from_email.clone();
+		}
+		if rule.smtp_timeout_secs.is_some() {
+			self.smtp_timeout = Some(limits.smtp_timeout(rule.smtp_timeout_secs));
+		}
+	}
 }
 
 pub struct TaskInputIterator {
@@ -52,7 +221,9 @@ impl IntoIterator for TaskInput {
 	}
 }
 
-/// Iterate through all the `smtp_ports`.
+/// Iterate through all the `smtp_ports`. Whether the next port should
+/// actually be tried is decided by the caller, see
+/// [`SmtpPortFallback::should_fall_back`].
 impl Iterator for TaskInputIterator {
 	type Item = CheckEmailInput;
 
@@ -68,13 +239,18 @@ impl Iterator for TaskInputIterator {
 				item.set_from_email(email.clone());
 			}
 
-			item.set_smtp_port(self.body.smtp_ports[self.index]);
+			let smtp_port = self.body.smtp_ports[self.index];
+			item.set_smtp_port(smtp_port.port());
+			item.set_smtp_security(smtp_port.security().into());
 
 			if let Some(proxy) = &self.body.proxy {
 				item.set_proxy(proxy.clone());
 			}
 
-			item.set_smtp_timeout(Duration::from_secs(SMTP_TIMEOUT));
+			item.set_smtp_timeout(Duration::from_secs(
+				self.body.smtp_timeout.unwrap_or(SMTP_TIMEOUT),
+			));
+			item.set_retries(self.body.retries.unwrap_or(SMTP_RETRIES));
 
 			self.index += 1;
 			Some(item)
@@ -89,21 +265,112 @@ impl Iterator for TaskInputIterator {
 struct TaskPayload {
 	id: i32,
 	input: TaskInput,
+	/// Number of times this task has already been rescheduled because of a
+	/// transient SMTP error (e.g. greylisting).
+	#[serde(default)]
+	attempt: u32,
+	/// Position of the email in the job's input, starting at 0. Older tasks
+	/// don't have it.
+	#[serde(default)]
+	row_index: Option<i32>,
+	/// The input row as sent by the user, before normalization: the bare
+	/// email, or the JSON-serialized object carrying the caller's metadata.
+	#[serde(default)]
+	raw_input: Option<String>,
+	#[serde(default)]
+	priority: JobPriority,
+	/// Trace context of the request which submitted the task.
+	#[serde(default)]
+	trace_context: HashMap<String, String>,
+}
+
+/// Longest delay before retrying a task after a transient error.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Policy for rescheduling tasks which got a transient SMTP error, typically
+/// because of greylisting. The `n`-th retry is delayed by `base_delay * 2^n`.
+#[derive(Clone, Copy, Debug)]
+pub struct GreylistingRetry {
+	/// Maximum number of times a task is rescheduled before its `unknown`
+	/// result is written to the database.
+	max_attempts: u32,
+	base_delay: Duration,
+}
+
+impl GreylistingRetry {
+	pub fn new(max_attempts: u32, base_delay_secs: u64) -> Self {
+		GreylistingRetry {
+			max_attempts,
+			base_delay: Duration::from_secs(base_delay_secs),
+		}
+	}
+
+	/// Delay before running the task again, or `None` if all attempts have
+	/// been exhausted.
+	fn next_delay(&self, attempt: u32) -> Option<Duration> {
+		if attempt >= self.max_attempts {
+			return None;
+		}
+
+		// Saturate on silly configurations instead of overflowing.
+		let delay = self
+			.base_delay
+			.checked_mul(2u32.pow(attempt.min(16)))
+			.unwrap_or(MAX_RETRY_DELAY);
+		Some(delay.min(MAX_RETRY_DELAY))
+	}
+}
+
+/// Check if the SMTP server answered with a transient (4xx) error, which
+/// means we should try again later.
+fn has_transient_smtp_error(response: &CheckEmailOutput) -> bool {
+	response.is_reachable == Reachable::Unknown
+		&& matches!(
+			response.smtp,
+			Err(SmtpError::SmtpError(AsyncSmtpError::Transient(_)))
+		)
 }
 
 pub async fn submit_job(
 	conn_pool: &Pool<Postgres>,
 	job_id: i32,
+	priority: JobPriority,
+	row_index: i32,
+	raw_input: String,
 	task_input: TaskInput,
 ) -> Result<Uuid, BulkError> {
 	let task_payload = TaskPayload {
 		id: job_id,
 		input: task_input,
+		attempt: 0,
+		row_index: Some(row_index),
+		raw_input: Some(raw_input),
+		priority,
+		trace_context: inject_context(&Span::current()),
 	};
 
+	spawn_task(conn_pool, &task_payload, Duration::ZERO).await
+}
+
+/// Spawn a sqlxmq task, which will start after the given delay.
+async fn spawn_task<'e, E>(
+	executor: E,
+	task_payload: &TaskPayload,
+	delay: Duration,
+) -> Result<Uuid, BulkError>
+where
+	E: sqlx::Executor<'e, Database = Postgres>,
+{
+	// Each job gets its own channel args, so that the runners poll tasks
+	// from all running jobs in turn, instead of processing the jobs one
+	// after the other.
+	let channel_args = task_payload.id.to_string();
 	let uuid = email_verification_task
 		.builder()
-		.set_json(&task_payload)
+		.set_channel_name(task_payload.priority.channel_name())
+		.set_channel_args(&channel_args)
+		.set_delay(delay)
+		.set_json(task_payload)
 		.map_err(|e| {
 			log::error!(
 				target: "reacher",
@@ -114,13 +381,13 @@ pub async fn submit_job(
 
 			BulkError::Json(e)
 		})?
-		.spawn(conn_pool)
+		.spawn(executor)
 		.await
 		.map_err(|e| {
 			log::error!(
 				target: "reacher",
 				"Failed to submit task for [bulk_req={}] with [error={}]",
-				job_id,
+				task_payload.id,
 				e
 			);
 
@@ -141,60 +408,214 @@ pub async fn submit_job(
 /// Please be careful while reading code.
 #[job]
 pub async fn email_verification_task(
-	mut current_job: CurrentJob,
+	current_job: CurrentJob,
 	// Additional arguments are optional, but can be used to access context
 	// provided via [`JobRegistry::set_context`].
+	throttle: Arc<DomainThrottle>,
+	retry: GreylistingRetry,
+	tracker: Arc<TaskTracker>,
+	limits: SmtpLimits,
+	state: Arc<AppState>,
 ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+	// Let graceful shutdown wait for this task to finish.
+	let _guard = tracker.start();
 	let task_payload: TaskPayload = current_job.json()?.ok_or("Got empty task.")?;
-	let job_id = task_payload.id;
 
-	let mut final_response: Option<CheckEmailOutput> = None;
+	// Attach this task to the trace of the request which submitted it.
+	let span = tracing::info_span!(
+		"email_verification_task",
+		job_id = task_payload.id,
+		task_uuid = %current_job.id(),
+		attempt = task_payload.attempt,
+		domain = tracing::field::Empty,
+	);
+	span.set_parent(extract_context(&task_payload.trace_context));
+
+	run_task(current_job, task_payload, throttle, retry, &limits, &state)
+		.instrument(span)
+		.await
+}
+
+/// The last response of a task, with the SMTP port and the proxy it was
+/// obtained on.
+#[derive(Debug)]
+pub struct TaskOutput {
+	pub smtp_port: u16,
+	/// Without its credentials.
+	pub proxy: Option<String>,
+	pub response: CheckEmailOutput,
+	/// Whether any of the attempted ports answered with a transient SMTP
+	/// error, even if a later port gave another response.
+	pub transient: bool,
+}
+
+/// Verify the email of a task on its `smtp_ports`, moving on to the next
+/// port according to its `smtp_port_fallback`. The routing rule matching
+/// the email, if any, overrides the task's options, within `limits`. Tasks
+/// without a proxy use the rule's proxy pool, else the default one, if any.
+pub async fn check_task_input(
+	mut input: TaskInput,
+	throttle: &DomainThrottle,
+	limits: &SmtpLimits,
+	state: &AppState,
+) -> Option<TaskOutput> {
+	// Time left to verify the email, shared by the routing and all ports.
+	// Time spent waiting on the throttle doesn't count.
+	let mut time_left = input.deadline.map(Duration::from_secs);
+	let rule = match input.to_emails.first() {
+		Some(email) => state.router.route(email, &mut time_left).await,
+		None => None,
+	};
+	if let Some(rule) = rule {
+		input.apply_rule(rule, limits);
+	}
+	let pool = proxy_pool_for(&state.proxies, rule);
+	let fallback = input.smtp_port_fallback;
+	let mut final_response: Option<TaskOutput> = None;
+	let mut transient = false;
 
-	for check_email_input in task_payload.input {
+	for check_email_input in input {
 		log::debug!(
 			target:"reacher",
-			"Starting task [email={}] for [job={}] and [uuid={}]",
+			"Starting task [email={}] on [port={}]",
 			check_email_input.to_emails[0],
-			task_payload.id,
-			current_job.id(),
+			check_email_input.smtp_port,
 		);
 
-		let response = check_email(&check_email_input).await;
-
-		log::debug!(
-			target:"reacher",
-			"Got task result [email={}] for [job={}] and [uuid={}] with [is_reachable={:?}]",
+		// Wait for our turn on this domain, to avoid opening too many SMTP
+		// connections to the same provider.
+		let domain = check_email_input.to_emails[0]
+			.rsplit_once('@')
+			.map(|(_, domain)| domain)
+			.unwrap_or_default();
+		Span::current().record("domain", domain);
+		let permit = throttle
+			.acquire(domain)
+			.instrument(tracing::debug_span!("throttle"))
+			.await;
+		let started_at = Instant::now();
+		let (response, proxy) =
+			check_email_with_proxy(&check_email_input, pool, time_left, state).await;
+		let elapsed = started_at.elapsed();
+		time_left = time_left.map(|t| t.saturating_sub(elapsed));
+		drop(permit);
+
+		tracing::debug!(
+			target: "reacher",
+			duration_ms = elapsed.as_millis() as u64,
+			"Got task result [email={}] on [port={}] with [is_reachable={:?}]",
 			check_email_input.to_emails[0],
-			task_payload.id,
-			current_job.id(),
+			check_email_input.smtp_port,
 			response.is_reachable,
 		);
 
-		let is_reachable = response.is_reachable == Reachable::Unknown;
-		final_response = Some(response);
+		let should_fall_back =
+			fallback.should_fall_back(&response) && time_left != Some(Duration::ZERO);
+		transient |= has_transient_smtp_error(&response);
+		final_response = Some(TaskOutput {
+			smtp_port: check_email_input.smtp_port,
+			proxy,
+			response,
+			transient,
+		});
 		// unsuccessful validation continue iteration with next possible smtp port
-		if is_reachable {
+		if should_fall_back {
 			continue;
 		}
-		// successful validation attempt complete job break iteration
+		// successful validation attempt, or definitive answer from the
+		// server: complete job break iteration
 		else {
 			break;
 		}
 	}
 
+	final_response
+}
+
+/// The result stored for a task, recording which port and proxy gave it.
+pub fn task_result(output: &TaskOutput, classifier: &ErrorClassifier) -> serde_json::Value {
+	let mut result = classifier.to_json(&output.response);
+	result["debug"] = serde_json::json!({ "smtp_port": output.smtp_port });
+	if let Some(proxy) = &output.proxy {
+		result["debug"]["proxy"] = proxy.as_str().into();
+	}
+
+	result
+}
+
+/// Verify the email of a task, and store the result or reschedule the task.
+async fn run_task(
+	mut current_job: CurrentJob,
+	task_payload: TaskPayload,
+	throttle: Arc<DomainThrottle>,
+	retry: GreylistingRetry,
+	limits: &SmtpLimits,
+	state: &AppState,
+) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+	let job_id = task_payload.id;
+
+	// The last response we got, with the SMTP port it was obtained on.
+	let final_response =
+		check_task_input(task_payload.input.clone(), &throttle, limits, state).await;
+
+	// On transient errors on any port, reschedule the task later instead of
+	// storing the result, until we run out of attempts. The last port may
+	// have failed for another reason after the first one greylisted us.
+	if let Some(TaskOutput { response, .. }) = final_response
+		.as_ref()
+		.filter(|o| o.transient && o.response.is_reachable == Reachable::Unknown)
+	{
+		if let Some(delay) = retry.next_delay(task_payload.attempt) {
+			let next_payload = TaskPayload {
+				attempt: task_payload.attempt + 1,
+				..task_payload
+			};
+
+			// Spawning the new task and completing the current one happen in
+			// the same transaction, so that the email is never lost nor
+			// verified twice.
+			let mut tx = current_job.pool().begin().await?;
+			let task_uuid = spawn_task(&mut tx, &next_payload, delay).await?;
+			current_job.complete_with_transaction(tx).await?;
+
+			log::debug!(
+				target:"reacher",
+				"Rescheduled [email={}] for [job={}] with [uuid={}] in [delay={}s] after [attempt={}]",
+				response.input,
+				job_id,
+				task_uuid,
+				delay.as_secs(),
+				next_payload.attempt,
+			);
+
+			return Ok(());
+		}
+	}
+
 	// final response can only be empty if there
 	// were no validation attempts. This can can
 	// never occur currently
-	if let Some(response) = final_response {
+	if let Some(output) = final_response {
+		let response = &output.response;
+		let stored = state.keyring.protect(
+			task_result(&output, &state.classifier),
+			task_payload.raw_input.clone(),
+		);
+
 		// write results and terminate iteration
 		#[allow(unused_variables)]
 		let rec = sqlx::query!(
 			r#"
-			INSERT INTO email_results (job_id, result)
-			VALUES ($1, $2)
+			INSERT INTO email_results (job_id, result, external_id, meta, row_index, raw_input, normalized_email)
+			VALUES ($1, $2, $3, $4, $5, $6, $7)
 			"#,
 			job_id,
-			serde_json::json!(response)
+			stored.result,
+			task_payload.input.external_id,
+			task_payload.input.meta,
+			task_payload.row_index,
+			stored.raw_input,
+			stored.normalized_email,
 		)
 		// TODO: This is a simplified solution and will work when
 		// the job queue and email results tables are in the same
@@ -228,3 +649,89 @@ pub async fn email_verification_task(
 	current_job.complete().await?;
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{GreylistingRetry, SmtpPort, SmtpPortSecurity, TaskInput, MAX_RETRY_DELAY};
+	use crate::check::SmtpLimits;
+	use crate::routing::RoutingRule;
+	use std::time::Duration;
+
+	#[test]
+	fn test_smtp_ports_deserialize() {
+		// Older tasks only have port numbers.
+		let input: TaskInput = serde_json::from_str(
+			r#"{"to_emails":["foo@bar.baz"],"smtp_ports":[25,465,{"port":587,"security":"starttls"}],"proxy":null,"hello_name":null,"from_email":null}"#,
+		)
+		.unwrap();
+
+		assert_eq!(
+			input.smtp_ports,
+			vec![
+				SmtpPort::Port(25),
+				SmtpPort::Port(465),
+				SmtpPort::WithSecurity {
+					port: 587,
+					security: SmtpPortSecurity::Starttls
+				}
+			]
+		);
+		assert_eq!(
+			input.smtp_ports[0].security(),
+			SmtpPortSecurity::Opportunistic
+		);
+		assert_eq!(
+			input.smtp_ports[1].security(),
+			SmtpPortSecurity::ImplicitTls
+		);
+		assert_eq!(input.smtp_ports[2].port(), 587);
+		assert_eq!(input.smtp_ports[2].security(), SmtpPortSecurity::Starttls);
+	}
+
+	#[test]
+	fn test_apply_rule() {
+		let mut input: TaskInput = serde_json::from_str(
+			r#"{"to_emails":["foo@yahoo.com"],"smtp_ports":[25,465],"proxy":{"host":"my-proxy.io","port":1080},"hello_name":"foo.com","from_email":null,"smtp_timeout":10}"#,
+		)
+		.unwrap();
+		let mut rule = RoutingRule {
+			domains: vec!["yahoo.com".into()],
+			proxy_pool: Some("main".into()),
+			smtp_ports: Some(vec![587]),
+			from_email: Some("hello@bar.com".into()),
+			..Default::default()
+		};
+		let limits = SmtpLimits::default();
+		input.apply_rule(&rule, &limits);
+
+		assert_eq!(input.smtp_ports, vec![SmtpPort::Port(587)]);
+		assert!(input.proxy.is_none());
+		assert_eq!(input.hello_name.as_deref(), Some("foo.com"));
+		assert_eq!(input.from_email.as_deref(), Some("hello@bar.com"));
+		assert_eq!(input.smtp_timeout, Some(10));
+
+		// The rule's timeout is still bounded by the server.
+		rule.smtp_timeout_secs = Some(3600);
+		input.apply_rule(&rule, &limits);
+		assert_eq!(input.smtp_timeout, Some(limits.max_smtp_timeout));
+	}
+
+	#[test]
+	fn test_greylisting_next_delay() {
+		let retry = GreylistingRetry {
+			max_attempts: 3,
+			base_delay: Duration::from_secs(60),
+		};
+
+		assert_eq!(retry.next_delay(0), Some(Duration::from_secs(60)));
+		assert_eq!(retry.next_delay(1), Some(Duration::from_secs(120)));
+		assert_eq!(retry.next_delay(2), Some(Duration::from_secs(240)));
+		assert_eq!(retry.next_delay(3), None);
+
+		let retry = GreylistingRetry {
+			max_attempts: 20,
+			base_delay: Duration::from_secs(u64::MAX),
+		};
+		assert_eq!(retry.next_delay(19), Some(MAX_RETRY_DELAY));
+	}
+}
@@ -0,0 +1,304 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Per-domain throttling of the SMTP connections opened by the bulk worker.
+//!
+//! The job runner's concurrency settings are global: with 20 concurrent
+//! tasks, a job full of `@gmail.com` addresses opens 20 parallel SMTP
+//! sessions to Gmail, which quickly gets us rate-limited. The throttle below
+//! caps the number of concurrent checks per domain, and enforces a minimum
+//! interval between two consecutive checks on the same domain.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep_until, Instant};
+
+/// Above this number of buckets, idle buckets are dropped from memory.
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// Limits applied to all checks on one domain (or one provider).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThrottleLimits {
+	/// Maximum number of concurrent checks. `None` means unlimited.
+	pub concurrency: Option<usize>,
+	/// Minimum interval between the start of two checks.
+	pub min_interval: Duration,
+}
+
+impl ThrottleLimits {
+	fn is_unlimited(&self) -> bool {
+		self.concurrency.is_none() && self.min_interval.is_zero()
+	}
+}
+
+/// A provider-specific rule. All the listed domains (and their subdomains)
+/// share the same bucket, so that e.g. `hotmail.com` and `outlook.com` count
+/// towards the same Microsoft limit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ThrottleRule {
+	domains: Vec<String>,
+	limits: ThrottleLimits,
+}
+
+impl ThrottleRule {
+	fn matches(&self, domain: &str) -> bool {
+		self.domains
+			.iter()
+			.any(|d| domain == d || domain.ends_with(&format!(".{}", d)))
+	}
+}
+
+/// State shared by all checks hitting the same bucket.
+struct Bucket {
+	semaphore: Option<Arc<Semaphore>>,
+	min_interval: Duration,
+	/// Earliest instant at which the next check may start.
+	next_start: AsyncMutex<Instant>,
+}
+
+impl Bucket {
+	fn new(limits: ThrottleLimits) -> Self {
+		Bucket {
+			semaphore: limits.concurrency.map(|n| Arc::new(Semaphore::new(n))),
+			min_interval: limits.min_interval,
+			next_start: AsyncMutex::new(Instant::now()),
+		}
+	}
+}
+
+/// Permit returned by [`DomainThrottle::acquire`]. The concurrency slot is
+/// released when the permit is dropped.
+#[must_use]
+pub struct ThrottlePermit {
+	_permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Per-domain semaphores and rate limits, shared by all bulk tasks running in
+/// this process. It is passed to `email_verification_task` as a job registry
+/// context.
+pub struct DomainThrottle {
+	default: ThrottleLimits,
+	rules: Vec<ThrottleRule>,
+	buckets: Mutex<HashMap<String, Arc<Bucket>>>,
+}
+
+impl DomainThrottle {
+	/// Create a new throttle, with default limits applied to each domain not
+	/// matched by any rule.
+	fn new(default: ThrottleLimits, rules: Vec<ThrottleRule>) -> Self {
+		DomainThrottle {
+			default,
+			rules,
+			buckets: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Create the throttle from its configuration:
+	/// - `concurrency`: default max concurrent checks per domain,
+	/// - `min_interval_ms`: default interval between two checks on the same
+	///   domain,
+	/// - `rules`: per-provider overrides, in the format
+	///   `gmail.com,googlemail.com=5:1000;hotmail.com,outlook.com=2:2000`,
+	///   where `5:1000` means 5 concurrent checks and 1000ms between checks.
+	///
+	/// A concurrency of 0 is rejected, as no check could ever run.
+	pub fn from_config(
+		concurrency: Option<usize>,
+		min_interval_ms: u64,
+		rules: &str,
+	) -> Result<Self, String> {
+		if concurrency == Some(0) {
+			return Err("default concurrency must be positive".into());
+		}
+
+		Ok(DomainThrottle::new(
+			ThrottleLimits {
+				concurrency,
+				min_interval: Duration::from_millis(min_interval_ms),
+			},
+			parse_rules(rules)?,
+		))
+	}
+
+	/// Find the bucket key and the limits applying to a domain.
+	fn limits_for(&self, domain: &str) -> (String, ThrottleLimits) {
+		match self.rules.iter().find(|rule| rule.matches(domain)) {
+			// All domains of a rule share the bucket of the rule's 1st domain.
+			Some(rule) => (rule.domains[0].clone(), rule.limits),
+			None => (domain.to_string(), self.default),
+		}
+	}
+
+	fn bucket(&self, key: String, limits: ThrottleLimits) -> Arc<Bucket> {
+		let mut buckets = self.buckets.lock().expect("Throttle lock poisoned. qed.");
+
+		if buckets.len() > MAX_IDLE_BUCKETS {
+			// Only the map holds a reference to idle buckets.
+			buckets.retain(|_, bucket| Arc::strong_count(bucket) > 1);
+		}
+
+		buckets
+			.entry(key)
+			.or_insert_with(|| Arc::new(Bucket::new(limits)))
+			.clone()
+	}
+
+	/// Wait until a check on the given domain is allowed to start. The
+	/// returned permit should be held for the duration of the check.
+	pub async fn acquire(&self, domain: &str) -> ThrottlePermit {
+		let domain = domain.trim().to_lowercase();
+		let (key, limits) = self.limits_for(&domain);
+		if domain.is_empty() || limits.is_unlimited() {
+			return ThrottlePermit { _permit: None };
+		}
+
+		let bucket = self.bucket(key, limits);
+		let permit = match &bucket.semaphore {
+			Some(semaphore) => Some(
+				semaphore
+					.clone()
+					.acquire_owned()
+					.await
+					.expect("Throttle semaphore is never closed. qed."),
+			),
+			None => None,
+		};
+
+		if !bucket.min_interval.is_zero() {
+			// Holding the lock while sleeping makes concurrent checks on the
+			// same bucket start one after the other.
+			let mut next_start = bucket.next_start.lock().await;
+			sleep_until(*next_start).await;
+			*next_start = Instant::now() + bucket.min_interval;
+		}
+
+		ThrottlePermit { _permit: permit }
+	}
+}
+
+/// Parse the per-provider throttle rules.
+fn parse_rules(input: &str) -> Result<Vec<ThrottleRule>, String> {
+	input
+		.split(';')
+		.map(str::trim)
+		.filter(|rule| !rule.is_empty())
+		.map(|rule| {
+			let (domains, limits) = rule
+				.split_once('=')
+				.ok_or_else(|| format!("missing '=' in rule \"{}\"", rule))?;
+			let (concurrency, interval) = limits
+				.split_once(':')
+				.ok_or_else(|| format!("missing ':' in rule \"{}\"", rule))?;
+			let concurrency = concurrency
+				.trim()
+				.parse::<usize>()
+				.map_err(|e| format!("invalid concurrency in rule \"{}\": {}", rule, e))?;
+			let interval = interval
+				.trim()
+				.parse::<u64>()
+				.map_err(|e| format!("invalid interval in rule \"{}\": {}", rule, e))?;
+			let domains: Vec<String> = domains
+				.split(',')
+				.map(|d| d.trim().to_lowercase())
+				.filter(|d| !d.is_empty())
+				.collect();
+			if domains.is_empty() {
+				return Err(format!("no domain in rule \"{}\"", rule));
+			}
+			if concurrency == 0 {
+				return Err(format!("concurrency must be positive in rule \"{}\"", rule));
+			}
+
+			Ok(ThrottleRule {
+				domains,
+				limits: ThrottleLimits {
+					concurrency: Some(concurrency),
+					min_interval: Duration::from_millis(interval),
+				},
+			})
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_rules() {
+		let rules = parse_rules("gmail.com, googlemail.com=5:1000; outlook.com=2:0;").unwrap();
+		assert_eq!(rules.len(), 2);
+		assert_eq!(rules[0].domains, vec!["gmail.com", "googlemail.com"]);
+		assert_eq!(rules[0].limits.concurrency, Some(5));
+		assert_eq!(rules[0].limits.min_interval, Duration::from_millis(1000));
+		assert_eq!(rules[1].domains, vec!["outlook.com"]);
+
+		assert!(parse_rules("gmail.com").is_err());
+		assert!(parse_rules("gmail.com=5").is_err());
+		assert!(parse_rules("gmail.com=0:10").is_err());
+		assert!(parse_rules("=1:10").is_err());
+	}
+
+	#[test]
+	fn test_from_config() {
+		assert!(DomainThrottle::from_config(Some(1), 0, "").is_ok());
+		assert!(DomainThrottle::from_config(Some(0), 0, "").is_err());
+		assert!(DomainThrottle::from_config(None, 0, "gmail.com=0:10").is_err());
+	}
+
+	#[test]
+	fn test_limits_for() {
+		let default = ThrottleLimits {
+			concurrency: None,
+			min_interval: Duration::ZERO,
+		};
+		let throttle =
+			DomainThrottle::new(default, parse_rules("hotmail.com,outlook.com=2:0").unwrap());
+
+		let (key, limits) = throttle.limits_for("eu.outlook.com");
+		assert_eq!(key, "hotmail.com");
+		assert_eq!(limits.concurrency, Some(2));
+		assert_eq!(
+			throttle.limits_for("notoutlook.com"),
+			("notoutlook.com".into(), default)
+		);
+	}
+
+	#[tokio::test]
+	async fn test_acquire_concurrency() {
+		let throttle = DomainThrottle::new(
+			ThrottleLimits {
+				concurrency: Some(1),
+				min_interval: Duration::ZERO,
+			},
+			vec![],
+		);
+
+		let permit = throttle.acquire("gmail.com").await;
+		// Another domain has its own bucket.
+		let _other = throttle.acquire("yahoo.com").await;
+		assert!(
+			tokio::time::timeout(Duration::from_millis(50), throttle.acquire("gmail.com"))
+				.await
+				.is_err()
+		);
+
+		drop(permit);
+		let _permit = throttle.acquire("gmail.com").await;
+	}
+}
@@ -0,0 +1,135 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Tracking of the bulk tasks running in this process.
+//!
+//! sqlxmq spawns each task on its own tokio task, so stopping the job runner
+//! only stops fetching new tasks. On shutdown, we use the tracker below to
+//! wait for the running ones to finish.
+//!
+//! A task is only counted once its tokio task is first polled, which happens
+//! shortly after sqlxmq dispatches it. To catch tasks dispatched right before
+//! the runner stopped, [`TaskTracker::wait_idle`] waits for
+//! [`DISPATCH_GRACE`] and checks again once no task is running. A task which
+//! takes longer than that to be polled, e.g. on an overloaded runtime, is
+//! still missed, and will be retried by another worker after its sqlxmq
+//! retry backoff.
+
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Time given to the tasks dispatched by sqlxmq to start running.
+const DISPATCH_GRACE: Duration = Duration::from_millis(100);
+
+/// Counts the `email_verification_task`s running in this process. It is
+/// passed to the task as a job registry context.
+pub struct TaskTracker {
+	running: watch::Sender<usize>,
+}
+
+impl Default for TaskTracker {
+	fn default() -> Self {
+		TaskTracker {
+			running: watch::channel(0).0,
+		}
+	}
+}
+
+/// Guard returned by [`TaskTracker::start`]. The task is considered finished
+/// when the guard is dropped.
+#[must_use]
+pub struct TaskGuard<'a> {
+	tracker: &'a TaskTracker,
+}
+
+impl Drop for TaskGuard<'_> {
+	fn drop(&mut self) {
+		self.tracker.running.send_modify(|count| *count -= 1);
+	}
+}
+
+impl TaskTracker {
+	/// Mark a task as running, until the returned guard is dropped.
+	pub fn start(&self) -> TaskGuard<'_> {
+		self.running.send_modify(|count| *count += 1);
+		TaskGuard { tracker: self }
+	}
+
+	/// Number of tasks currently running.
+	pub fn running(&self) -> usize {
+		*self.running.borrow()
+	}
+
+	/// Wait until no task is running, and none started during the following
+	/// [`DISPATCH_GRACE`].
+	pub async fn wait_idle(&self) {
+		let mut rx = self.running.subscribe();
+		loop {
+			while *rx.borrow_and_update() > 0 {
+				if rx.changed().await.is_err() {
+					return;
+				}
+			}
+
+			tokio::time::sleep(DISPATCH_GRACE).await;
+			if *rx.borrow() == 0 {
+				return;
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+
+	#[tokio::test]
+	async fn test_wait_idle() {
+		let tracker = TaskTracker::default();
+		tracker.wait_idle().await;
+
+		let guard = tracker.start();
+		assert_eq!(tracker.running(), 1);
+		assert!(
+			tokio::time::timeout(Duration::from_millis(50), tracker.wait_idle())
+				.await
+				.is_err()
+		);
+
+		drop(guard);
+		assert_eq!(tracker.running(), 0);
+		tracker.wait_idle().await;
+	}
+
+	#[tokio::test]
+	async fn test_wait_idle_late_start() {
+		let tracker = Arc::new(TaskTracker::default());
+
+		// A task dispatched right before shutdown, but not running yet.
+		let task = {
+			let tracker = tracker.clone();
+			tokio::spawn(async move {
+				tokio::time::sleep(Duration::from_millis(20)).await;
+				let _guard = tracker.start();
+				tokio::time::sleep(Duration::from_millis(50)).await;
+			})
+		};
+
+		tracker.wait_idle().await;
+		assert!(task.is_finished());
+	}
+}
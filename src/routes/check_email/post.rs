@@ -16,30 +16,62 @@
 
 //! This file implements the `POST /check_email` endpoint.
 
-use crate::check::check_email;
+use crate::config::Config;
+use crate::proxy::check_email_with_proxy;
+use crate::routes::{with_config, with_state};
+use crate::routing::{proxy_pool_for, RoutingRule};
+use crate::state::AppState;
 use check_if_email_exists::{CheckEmailInput, CheckEmailInputProxy};
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::{sync::Arc, time::Duration};
 use warp::Filter;
 
 /// Endpoint request body.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct EndpointRequest {
 	from_email: Option<String>,
 	hello_name: Option<String>,
 	proxy: Option<CheckEmailInputProxy>,
 	smtp_port: Option<u16>,
+	/// Timeout of each SMTP connection, in seconds.
+	smtp_timeout: Option<u64>,
+	/// Number of SMTP connection retries.
+	retries: Option<usize>,
+	/// Maximum duration of the whole verification, in seconds.
+	deadline: Option<u64>,
 	to_email: String,
 }
 
-impl From<EndpointRequest> for CheckEmailInput {
-	fn from(req: EndpointRequest) -> Self {
-		// Create Request for check_if_email_exists from body
+impl EndpointRequest {
+	/// A request with the server's default options.
+	pub fn new(to_email: String) -> Self {
+		EndpointRequest {
+			to_email,
+			..Default::default()
+		}
+	}
+
+	/// Create the input of `check_if_email_exists`, using the server's
+	/// defaults and limits. The options of the routing `rule` matching the
+	/// email, if any, take precedence over the request's.
+	pub fn into_input(self, config: &Config, rule: Option<&RoutingRule>) -> CheckEmailInput {
+		let mut req = self;
+		if let Some(rule) = rule {
+			req.from_email = rule.from_email.clone().or(req.from_email);
+			req.hello_name = rule.hello_name.clone().or(req.hello_name);
+			// The proxy then comes from the rule's pool.
+			if rule.proxy_pool.is_some() {
+				req.proxy = None;
+			}
+			if let Some(port) = rule.smtp_ports.as_ref().and_then(|p| p.first()) {
+				req.smtp_port = Some(*port);
+			}
+			req.smtp_timeout = rule.smtp_timeout_secs.or(req.smtp_timeout);
+		}
+
 		let mut input = CheckEmailInput::new(vec![req.to_email]);
 		input
-			.set_from_email(req.from_email.unwrap_or_else(|| {
-				env::var("RCH_FROM_EMAIL").unwrap_or_else(|_| "user@example.org".into())
-			}))
+			.set_from_email(req.from_email.unwrap_or_else(|| config.from_email.clone()))
 			.set_hello_name(req.hello_name.unwrap_or_else(|| "gmail.com".into()));
 
 		if let Some(proxy_input) = req.proxy {
@@ -50,26 +82,45 @@ impl From<EndpointRequest> for CheckEmailInput {
 			input.set_smtp_port(smtp_port);
 		}
 
+		let limits = config.smtp;
+		input
+			.set_smtp_timeout(Duration::from_secs(limits.smtp_timeout(req.smtp_timeout)))
+			.set_retries(limits.retries(req.retries));
+
 		input
 	}
 }
 
 /// The main endpoint handler that implements the logic of this route.
-async fn handler(body: EndpointRequest) -> Result<impl warp::Reply, warp::Rejection> {
-	// Run the future to check an email.
-	Ok(warp::reply::json(&check_email(&body.into()).await))
+async fn handler(
+	config: Arc<Config>,
+	state: Arc<AppState>,
+	body: EndpointRequest,
+) -> Result<impl warp::Reply, warp::Rejection> {
+	let mut deadline = config.smtp.deadline(body.deadline).map(Duration::from_secs);
+
+	// Run the future to check an email, through the pool of the matching
+	// routing rule (or the default pool) if the request has no proxy.
+	let rule = state.router.route(&body.to_email, &mut deadline).await;
+	let input = body.into_input(&config, rule);
+	let pool = proxy_pool_for(&state.proxies, rule);
+	let (output, _) = check_email_with_proxy(&input, pool, deadline, &state).await;
+
+	Ok(warp::reply::json(&state.classifier.to_json(&output)))
 }
 
 /// Create the `POST /check_email` endpoint.
 pub fn post_check_email(
+	config: Arc<Config>,
+	state: Arc<AppState>,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
 	warp::path!("v0" / "check_email")
 		.and(warp::post())
+		.and(with_config(config))
+		.and(with_state(state))
 		// When accepting a body, we want a JSON body (and to reject huge
 		// payloads)...
 		.and(warp::body::content_length_limit(1024 * 16))
 		.and(warp::body::json())
 		.and_then(handler)
-		// View access logs by setting `RUST_LOG=reacher`.
-		.with(warp::log("reacher"))
 }
@@ -0,0 +1,217 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! This file implements the `DELETE /v0/emails/{email}` endpoint, to erase
+//! all the data stored about an email address, e.g. for GDPR requests.
+
+use crate::check::normalize_email;
+use crate::config::Config;
+use crate::errors::ReacherResponseError;
+use crate::routes::{
+	auth::with_admin_auth,
+	bulk::{anonymize_results, with_db, BulkError, JanitorMode},
+	with_state,
+};
+use crate::state::AppState;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::{collections::HashMap, sync::Arc};
+use warp::{http::StatusCode, Filter};
+
+/// Query parameters.
+#[derive(Debug, Deserialize)]
+struct EraseRequest {
+	/// `delete` the results, or `anonymize` them to keep the verification
+	/// outcome for statistics. Defaults to `delete`.
+	mode: Option<JanitorMode>,
+}
+
+/// Endpoint response body.
+#[derive(Debug, Serialize)]
+struct EraseResponseBody {
+	mode: JanitorMode,
+	/// Number of stored results erased.
+	results: i64,
+	/// Number of queued verifications of the address removed.
+	tasks: i64,
+}
+
+/// Keyed hash of the normalized email, so that the audit entries don't store
+/// the address they're about: with the first encryption key, else the
+/// redaction salt. Without either, no hash is recorded, as it could be
+/// reversed by hashing candidate addresses.
+fn email_hash(state: &AppState, email: &str) -> Option<String> {
+	state
+		.keyring
+		.keyed_hash(email)
+		.or_else(|| state.redactor.salted_hash(email))
+}
+
+/// Erase the results of `email`, remove its queued tasks, and record an
+/// audit entry, all in one transaction. The erased emails are removed from
+/// the `total_records` of their jobs. A verification already running may
+/// still store its result afterwards.
+async fn erase_email(
+	conn_pool: &Pool<Postgres>,
+	state: &AppState,
+	email: &str,
+	mode: JanitorMode,
+) -> Result<EraseResponseBody, sqlx::Error> {
+	// Results may be stored under a hash of the email.
+	let lookups = state.keyring.email_lookups(email);
+	let mut tx = conn_pool.begin().await?;
+
+	let results = match mode {
+		JanitorMode::Delete => {
+			sqlx::query_scalar!(
+				r#"
+				WITH deleted AS (
+					DELETE FROM email_results WHERE normalized_email = ANY($1)
+					RETURNING job_id
+				), counts AS (
+					SELECT job_id, COUNT(*) AS count FROM deleted GROUP BY job_id
+				), updated AS (
+					UPDATE bulk_jobs SET total_records = total_records - counts.count
+					FROM counts WHERE bulk_jobs.id = counts.job_id
+				)
+				SELECT COALESCE(SUM(count), 0)::BIGINT AS "count!" FROM counts
+				"#,
+				&lookups
+			)
+			.fetch_one(&mut tx)
+			.await?
+		}
+		JanitorMode::Anonymize => {
+			let ids = sqlx::query_scalar!(
+				"SELECT id FROM email_results WHERE normalized_email = ANY($1)",
+				&lookups
+			)
+			.fetch_all(&mut tx)
+			.await?;
+
+			anonymize_results(&mut tx, &ids).await? as i64
+		}
+	};
+
+	// Tasks not run yet carry the email in their payload, where it is
+	// indexed by `mq_payloads_to_email`.
+	let tasks = sqlx::query!(
+		r#"
+		SELECT id, (payload_json ->> 'id')::INTEGER AS job_id FROM mq_payloads
+		WHERE LOWER(TRIM(payload_json -> 'input' -> 'to_emails' ->> 0)) = $1
+		"#,
+		email
+	)
+	.fetch_all(&mut tx)
+	.await?;
+	if !tasks.is_empty() {
+		let ids: Vec<_> = tasks.iter().map(|t| t.id).collect();
+		sqlx::query!("SELECT mq_delete($1)", &ids)
+			.execute(&mut tx)
+			.await?;
+
+		let mut counts: HashMap<i32, i32> = HashMap::new();
+		for job_id in tasks.iter().filter_map(|t| t.job_id) {
+			*counts.entry(job_id).or_default() += 1;
+		}
+		for (job_id, count) in counts {
+			sqlx::query!(
+				"UPDATE bulk_jobs SET total_records = total_records - $2 WHERE id = $1",
+				job_id,
+				count
+			)
+			.execute(&mut tx)
+			.await?;
+		}
+	}
+
+	sqlx::query!(
+		r#"
+		INSERT INTO email_erasures (email_hash, mode, results_count, tasks_count)
+		VALUES ($1, $2, $3, $4)
+		"#,
+		email_hash(state, email),
+		format!("{:?}", mode).to_lowercase(),
+		results as i32,
+		tasks.len() as i32,
+	)
+	.execute(&mut tx)
+	.await?;
+
+	tx.commit().await?;
+
+	Ok(EraseResponseBody {
+		mode,
+		results,
+		tasks: tasks.len() as i64,
+	})
+}
+
+/// The endpoint handler.
+async fn handler(
+	email: String,
+	req: EraseRequest,
+	state: Arc<AppState>,
+	conn_pool: Pool<Postgres>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+	let email = percent_encoding::percent_decode_str(&email)
+		.decode_utf8()
+		.map_err(|_| {
+			ReacherResponseError::new(StatusCode::BAD_REQUEST, "Email is not valid UTF-8")
+		})?;
+	let email = normalize_email(&email);
+	let mode = req.mode.unwrap_or_default();
+
+	let body = erase_email(&conn_pool, &state, &email, mode)
+		.await
+		.map_err(|e| {
+			log::error!(
+				target: "reacher",
+				"Failed to erase [email={}] with [error={}]",
+				email,
+				e
+			);
+
+			BulkError::from(e)
+		})?;
+
+	log::info!(
+		target: "reacher",
+		"Erased [email={}] with [mode={:?}]: [results={}] [tasks={}]",
+		email,
+		body.mode,
+		body.results,
+		body.tasks,
+	);
+
+	Ok(warp::reply::json(&body))
+}
+
+/// Create the `DELETE /v0/emails/{email}` endpoint. It needs the admin token,
+/// and 404s if bulk is disabled.
+pub fn delete_email(
+	config: Arc<Config>,
+	state: Arc<AppState>,
+	o: Option<Pool<Postgres>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+	warp::path!("v0" / "emails" / String)
+		.and(warp::delete())
+		.and(with_admin_auth(config))
+		.and(warp::query::<EraseRequest>())
+		.and(with_state(state))
+		.and(with_db(o))
+		.and_then(handler)
+}
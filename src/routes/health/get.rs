@@ -0,0 +1,244 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! This file implements the `GET /healthz` and `GET /readyz` endpoints.
+
+use crate::config::Config;
+use crate::routes::bulk::{JobRunners, MIGRATOR};
+use crate::sentry_util::CARGO_PKG_VERSION;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use warp::{http::StatusCode, Filter};
+
+/// Status of a single readiness check.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum CheckStatus {
+	Ok,
+	Failed,
+	/// The check doesn't apply to this process, e.g. the database checks
+	/// when bulk is disabled.
+	Skipped,
+}
+
+/// Result of a single readiness check.
+#[derive(Debug, Serialize)]
+struct Check {
+	status: CheckStatus,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	message: Option<String>,
+}
+
+impl Check {
+	fn ok() -> Self {
+		Check {
+			status: CheckStatus::Ok,
+			message: None,
+		}
+	}
+
+	fn failed(message: String) -> Self {
+		Check {
+			status: CheckStatus::Failed,
+			message: Some(message),
+		}
+	}
+
+	fn skipped() -> Self {
+		Check {
+			status: CheckStatus::Skipped,
+			message: None,
+		}
+	}
+}
+
+/// `GET /healthz` response body.
+#[derive(Debug, Serialize)]
+struct HealthResponseBody {
+	status: CheckStatus,
+	version: String,
+}
+
+/// `GET /readyz` response body.
+#[derive(Debug, Serialize)]
+struct ReadinessResponseBody {
+	status: CheckStatus,
+	database: Check,
+	migrations: Check,
+	job_runner: Check,
+	queue: Check,
+	/// Number of bulk tasks ready to be run.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	queue_depth: Option<i64>,
+}
+
+/// Create the `GET /healthz` endpoint. It always succeeds while the process
+/// is able to serve HTTP requests.
+pub fn get_healthz() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+{
+	warp::path("healthz").and(warp::get()).map(|| {
+		warp::reply::json(&HealthResponseBody {
+			status: CheckStatus::Ok,
+			version: CARGO_PKG_VERSION.into(),
+		})
+	})
+}
+
+/// Check that the database is reachable and that all the migrations embedded
+/// in this binary have been applied.
+async fn check_database(conn_pool: &Pool<Postgres>) -> (Check, Check) {
+	if let Err(e) = sqlx::query("SELECT 1").execute(conn_pool).await {
+		return (
+			Check::failed(e.to_string()),
+			Check::failed("database unreachable".into()),
+		);
+	}
+
+	let expected = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+	let applied = sqlx::query_scalar::<_, Option<i64>>(
+		"SELECT MAX(version) FROM _sqlx_migrations WHERE success",
+	)
+	.fetch_one(conn_pool)
+	.await;
+	let migrations = match applied {
+		Ok(applied) if applied.unwrap_or(0) >= expected => Check::ok(),
+		Ok(applied) => Check::failed(format!(
+			"latest applied migration is {}, expected {}",
+			applied.unwrap_or(0),
+			expected
+		)),
+		Err(e) => Check::failed(e.to_string()),
+	};
+
+	(Check::ok(), migrations)
+}
+
+/// Count the bulk tasks ready to be run, and compare it to the `max_depth`
+/// threshold, if set.
+async fn check_queue(conn_pool: &Pool<Postgres>, max_depth: Option<i64>) -> (Check, Option<i64>) {
+	let depth = sqlx::query_scalar::<_, i64>(
+		"SELECT COUNT(*) FROM mq_msgs WHERE id != uuid_nil() AND attempt_at <= NOW()",
+	)
+	.fetch_one(conn_pool)
+	.await;
+
+	match (depth, max_depth) {
+		(Ok(depth), Some(max)) if depth > max => (
+			Check::failed(format!("{} tasks in queue, above {}", depth, max)),
+			Some(depth),
+		),
+		(Ok(depth), _) => (Check::ok(), Some(depth)),
+		(Err(e), _) => (Check::failed(e.to_string()), None),
+	}
+}
+
+async fn readyz(
+	config: Arc<Config>,
+	o: Option<Pool<Postgres>>,
+	runners: Option<Arc<JobRunners>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+	let (database, migrations, queue, queue_depth) = match &o {
+		Some(conn_pool) => {
+			let (database, migrations) = check_database(conn_pool).await;
+			let (queue, queue_depth) = if database.status == CheckStatus::Ok {
+				check_queue(conn_pool, config.bulk.ready_max_queue_depth).await
+			} else {
+				(Check::failed("database unreachable".into()), None)
+			};
+			(database, migrations, queue, queue_depth)
+		}
+		None => (Check::skipped(), Check::skipped(), Check::skipped(), None),
+	};
+	let job_runner = match &runners {
+		Some(runners) if runners.is_alive().await => Check::ok(),
+		Some(_) => Check::failed("job runner stopped".into()),
+		None => Check::skipped(),
+	};
+
+	let status = if [&database, &migrations, &job_runner, &queue]
+		.iter()
+		.any(|c| c.status == CheckStatus::Failed)
+	{
+		CheckStatus::Failed
+	} else {
+		CheckStatus::Ok
+	};
+	let code = if status == CheckStatus::Ok {
+		StatusCode::OK
+	} else {
+		StatusCode::SERVICE_UNAVAILABLE
+	};
+
+	Ok(warp::reply::with_status(
+		warp::reply::json(&ReadinessResponseBody {
+			status,
+			database,
+			migrations,
+			job_runner,
+			queue,
+			queue_depth,
+		}),
+		code,
+	))
+}
+
+/// Create the `GET /readyz` endpoint. It fails with a 503 if one of the
+/// checks fails.
+pub fn get_readyz(
+	config: Arc<Config>,
+	o: Option<Pool<Postgres>>,
+	runners: Option<Arc<JobRunners>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+	warp::path("readyz")
+		.and(warp::get())
+		.and_then(move || readyz(config.clone(), o.clone(), runners.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{get_healthz, get_readyz};
+	use crate::config::Config;
+	use std::sync::Arc;
+	use warp::http::StatusCode;
+	use warp::test::request;
+
+	#[tokio::test]
+	async fn test_get_healthz() {
+		let resp = request()
+			.path("/healthz")
+			.method("GET")
+			.reply(&get_healthz())
+			.await;
+
+		assert_eq!(resp.status(), StatusCode::OK);
+	}
+
+	#[tokio::test]
+	async fn test_get_readyz_without_bulk() {
+		let resp = request()
+			.path("/readyz")
+			.method("GET")
+			.reply(&get_readyz(Arc::new(Config::default()), None, None))
+			.await;
+
+		assert_eq!(resp.status(), StatusCode::OK);
+		assert_eq!(
+			resp.body(),
+			r#"{"status":"ok","database":{"status":"skipped"},"migrations":{"status":"skipped"},"job_runner":{"status":"skipped"},"queue":{"status":"skipped"}}"#
+		);
+	}
+}
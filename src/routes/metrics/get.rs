@@ -0,0 +1,63 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! This file implements the `GET /metrics` endpoint.
+
+use crate::metrics;
+use warp::Filter;
+
+async fn get_metrics_handler() -> Result<impl warp::Reply, warp::Rejection> {
+	Ok(warp::reply::with_header(
+		metrics::render(),
+		"Content-Type",
+		"text/plain; version=0.0.4",
+	))
+}
+
+/// Create the `GET /metrics` endpoint, in the Prometheus text format. The
+/// bulk queue metrics are refreshed by [`metrics::spawn_bulk_metrics`], in
+/// the worker processes only.
+pub fn get_metrics() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
+{
+	warp::path("metrics")
+		.and(warp::get())
+		.and_then(get_metrics_handler)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::get_metrics;
+	use crate::metrics::observe_verification;
+	use check_if_email_exists::CheckEmailOutput;
+	use std::time::Duration;
+	use warp::http::StatusCode;
+	use warp::test::request;
+
+	#[tokio::test]
+	async fn test_get_metrics() {
+		observe_verification(&CheckEmailOutput::default(), None, Duration::from_secs(1));
+
+		let resp = request()
+			.path("/metrics")
+			.method("GET")
+			.reply(&get_metrics())
+			.await;
+
+		assert_eq!(resp.status(), StatusCode::OK);
+		let body = String::from_utf8(resp.body().to_vec()).unwrap();
+		assert!(body.contains("reacher_verifications_total"));
+	}
+}
@@ -14,22 +14,117 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod audit;
+mod auth;
 pub mod bulk;
 pub mod check_email;
+pub mod emails;
+mod health;
+mod metrics;
+mod request_id;
 mod version;
 
-use super::errors;
+use crate::config::Config;
+use crate::state::AppState;
+use bulk::JobRunners;
 use sqlx::{Pool, Postgres};
+use std::{convert::Infallible, sync::Arc};
 use warp::Filter;
 
+/// Pass the configuration to a handler.
+pub(crate) fn with_config(
+	config: Arc<Config>,
+) -> impl Filter<Extract = (Arc<Config>,), Error = Infallible> + Clone {
+	warp::any().map(move || config.clone())
+}
+
+/// Pass the process state to a handler.
+pub(crate) fn with_state(
+	state: Arc<AppState>,
+) -> impl Filter<Extract = (Arc<AppState>,), Error = Infallible> + Clone {
+	warp::any().map(move || state.clone())
+}
+
+/// The route of a request path: its variable segments, the job ids and the
+/// email of `/v0/emails/{email}`, are replaced by placeholders. This is what
+/// gets logged, traced and used as a metric label, so that no email leaks
+/// there and the number of label values stays bounded.
+pub(crate) fn route_label(path: &str) -> String {
+	let mut previous = "";
+	path.split('/')
+		.map(|segment| {
+			let label = if previous == "emails" {
+				"{email}"
+			} else if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+				"{id}"
+			} else {
+				segment
+			};
+			previous = segment;
+
+			label
+		})
+		.collect::<Vec<_>>()
+		.join("/")
+}
+
+/// Create the version, health, readiness and metrics routes, which are all
+/// that worker-only processes serve.
+pub fn create_probe_routes(
+	config: Arc<Config>,
+	o: Option<Pool<Postgres>>,
+	runners: Option<Arc<JobRunners>>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+	version::get::get_version()
+		.or(health::get::get_healthz())
+		.or(health::get::get_readyz(config, o, runners))
+		.or(metrics::get::get_metrics())
+}
+
+/// Create all the routes. `runners` are the job runners of this process, if
+/// any, checked by `/readyz`. All responses carry an `X-Request-Id` header,
+/// and the API requests are recorded in the audit log.
 pub fn create_routes(
+	config: Arc<Config>,
+	state: Arc<AppState>,
 	o: Option<Pool<Postgres>>,
+	runners: Option<Arc<JobRunners>>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-	version::get::get_version()
-		.or(check_email::post::post_check_email())
+	let probes = create_probe_routes(config.clone(), o.clone(), runners);
+	let api = check_email::post::post_check_email(config.clone(), state.clone())
 		// The 3 following routes will 404 if o is None.
-		.or(bulk::post::create_bulk_job(o.clone()))
+		.or(bulk::post::create_bulk_job(config.clone(), o.clone()))
 		.or(bulk::get::get_bulk_job_status(o.clone()))
-		.or(bulk::results::get_bulk_job_result(o))
-		.recover(errors::handle_rejection)
+		.or(bulk::results::get_bulk_job_result(state.clone(), o.clone()))
+		// Need the admin token.
+		.or(emails::delete::delete_email(
+			config.clone(),
+			state,
+			o.clone(),
+		))
+		.or(audit::get::get_audit_log(config.clone(), o.clone()))
+		// View access logs by setting `RUST_LOG=reacher`. Probes are not
+		// logged, they would drown the other requests.
+		.with(warp::log::custom(crate::telemetry::log_request));
+
+	// Only the API routes are audited.
+	audit::with_audit_log(config, o, request_id::with_request_id(probes.or(api)))
+		.with(warp::log::custom(crate::metrics::observe_http_request))
+		.with(warp::trace(crate::telemetry::http_request_span))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_route_label() {
+		assert_eq!(route_label("/v0/bulk/42/results"), "/v0/bulk/{id}/results");
+		assert_eq!(route_label("/v0/check_email"), "/v0/check_email");
+		assert_eq!(
+			route_label("/v0/emails/someone%40gmail.com"),
+			"/v0/emails/{email}"
+		);
+		assert_eq!(route_label("/v0/emails/12345"), "/v0/emails/{email}");
+	}
 }
@@ -0,0 +1,101 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Correlation of logs and responses with the `X-Request-Id` header.
+
+use crate::errors::handle_rejection;
+use tracing::Span;
+use uuid::Uuid;
+use warp::{http::HeaderValue, reply::Response, Filter, Rejection, Reply};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Whether an ID sent by the client can be used as is. It ends up in the logs
+/// and in the response headers, so it must be short and printable.
+fn is_valid(id: &str) -> bool {
+	!id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Use the request's `X-Request-Id` header, or generate a new ID. The ID is
+/// recorded on the current `http_request` span, so that it appears in all the
+/// logs of this request.
+fn request_id() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+	warp::header::optional::<String>(REQUEST_ID_HEADER).map(|id: Option<String>| {
+		let id = id
+			.filter(|id| is_valid(id))
+			.unwrap_or_else(|| Uuid::new_v4().to_string());
+		Span::current().record("request_id", id.as_str());
+		id
+	})
+}
+
+/// Wrap `routes` so that their responses, including the error ones, carry
+/// the ID of the request in the `X-Request-Id` header. Rejections are turned
+/// into JSON error bodies with [`handle_rejection`].
+pub fn with_request_id<F, R>(
+	routes: F,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+	F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+	R: Reply,
+{
+	let routes = routes
+		.map(|reply: R| Ok(reply.into_response()))
+		.or_else(|rejection| async move { Ok::<_, Rejection>((Err(rejection),)) });
+
+	request_id().and(routes).and_then(
+		|id: String, result: Result<Response, Rejection>| async move {
+			let mut response = match result {
+				Ok(response) => response,
+				Err(rejection) => handle_rejection(rejection, &id)?,
+			};
+			if let Ok(value) = HeaderValue::from_str(&id) {
+				response.headers_mut().insert(REQUEST_ID_HEADER, value);
+			}
+
+			Ok::<_, Rejection>(response)
+		},
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use warp::test::request;
+
+	#[tokio::test]
+	async fn test_request_id() {
+		let routes = with_request_id(warp::path("ok").map(warp::reply));
+
+		// An ID sent by the client is echoed.
+		let resp = request()
+			.path("/ok")
+			.header(REQUEST_ID_HEADER, "abc-123")
+			.reply(&routes)
+			.await;
+		assert_eq!(resp.headers()[REQUEST_ID_HEADER], "abc-123");
+
+		// Otherwise one is generated, and also put in error bodies.
+		let resp = request().path("/not_found").reply(&routes).await;
+		assert_eq!(resp.status(), 404);
+		let id = resp.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+		assert!(Uuid::parse_str(id).is_ok());
+		assert_eq!(
+			resp.body(),
+			&format!(r#"{{"message":"Not found","request_id":"{}"}}"#, id)
+		);
+	}
+}
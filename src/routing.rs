@@ -0,0 +1,171 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Per-domain routing of the verifications.
+//!
+//! Some providers only answer reliably through particular proxies, or with
+//! particular `hello_name` or `from_email` values. The `[[routing]]` rules
+//! of the config override these options for the emails of a domain, or
+//! whose domain is served by an MX host.
+
+use crate::proxy::{ProxyPool, ProxyPools};
+use check_if_email_exists::{mx::check_mx, syntax::check_syntax};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// A `[[routing]]` rule. Domains and MX hosts also match their subdomains.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingRule {
+	/// Domains of the emails, e.g. `yahoo.com`.
+	pub domains: Vec<String>,
+	/// MX hosts of the domain, e.g. `yahoodns.net`.
+	pub mx_hosts: Vec<String>,
+	/// Name of the `[[proxies.pools]]` to use, even if the request has its
+	/// own proxy.
+	pub proxy_pool: Option<String>,
+	/// SMTP ports to try, in order. The `/v0/check_email` endpoint only
+	/// uses the first one.
+	pub smtp_ports: Option<Vec<u16>>,
+	pub hello_name: Option<String>,
+	pub from_email: Option<String>,
+	pub smtp_timeout_secs: Option<u64>,
+}
+
+/// Whether `domain` is `pattern` or one of its subdomains.
+fn matches(pattern: &str, domain: &str) -> bool {
+	let pattern = pattern.trim().trim_end_matches('.').to_lowercase();
+	let domain = domain.trim().trim_end_matches('.').to_lowercase();
+
+	domain == pattern || domain.ends_with(&format!(".{}", pattern))
+}
+
+impl RoutingRule {
+	fn matches_domain(&self, domain: &str) -> bool {
+		self.domains.iter().any(|d| matches(d, domain))
+	}
+
+	fn matches_mx(&self, mx_hosts: &[String]) -> bool {
+		self.mx_hosts
+			.iter()
+			.any(|pattern| mx_hosts.iter().any(|host| matches(pattern, host)))
+	}
+}
+
+/// The routing rules.
+#[derive(Debug, Default)]
+pub struct Router {
+	rules: Vec<RoutingRule>,
+}
+
+impl Router {
+	pub fn new(rules: &[RoutingRule]) -> Self {
+		Router {
+			rules: rules.to_vec(),
+		}
+	}
+
+	/// The first rule matching the domain of `email`, else the first one
+	/// matching one of its MX hosts. The MX hosts are only looked up if no
+	/// domain matched and some rule needs them. The lookup counts against
+	/// `time_left`, the time left to verify the email, if any: no MX rule
+	/// matches if it runs out.
+	pub async fn route(
+		&self,
+		email: &str,
+		time_left: &mut Option<Duration>,
+	) -> Option<&RoutingRule> {
+		let syntax = check_syntax(email);
+		if let Some(rule) = self.rules.iter().find(|r| r.matches_domain(&syntax.domain)) {
+			return Some(rule);
+		}
+		if self.rules.iter().all(|r| r.mx_hosts.is_empty()) || syntax.domain.is_empty() {
+			return None;
+		}
+
+		let started_at = Instant::now();
+		let mx = match *time_left {
+			Some(timeout) => tokio::time::timeout(timeout, check_mx(&syntax)).await.ok(),
+			None => Some(check_mx(&syntax).await),
+		};
+		*time_left = time_left.map(|t| t.saturating_sub(started_at.elapsed()));
+
+		let mx_hosts: Vec<String> = match mx.map(|mx| mx.map(|mx| mx.lookup)) {
+			Some(Ok(Ok(lookup))) => lookup.iter().map(|mx| mx.exchange().to_string()).collect(),
+			_ => return None,
+		};
+		self.rules.iter().find(|r| r.matches_mx(&mx_hosts))
+	}
+}
+
+/// The proxy pool of a verification among `pools`: the one of its rule, if
+/// any, else the default one.
+pub fn proxy_pool_for<'a>(
+	pools: &'a ProxyPools,
+	rule: Option<&RoutingRule>,
+) -> Option<&'a ProxyPool> {
+	match rule.and_then(|r| r.proxy_pool.as_deref()) {
+		Some(name) => pools.get(name),
+		None => pools.default_pool(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_route() {
+		let router = Router::new(&[
+			RoutingRule {
+				domains: vec!["yahoo.com".into()],
+				hello_name: Some("yahoo".into()),
+				..Default::default()
+			},
+			RoutingRule {
+				domains: vec!["outlook.com".into(), "hotmail.com".into()],
+				hello_name: Some("microsoft".into()),
+				..Default::default()
+			},
+		]);
+
+		let mut time_left = None;
+		let hello_name = |rule: Option<&RoutingRule>| rule.and_then(|r| r.hello_name.clone());
+		assert_eq!(
+			hello_name(router.route("someone@Hotmail.com", &mut time_left).await),
+			Some("microsoft".into())
+		);
+		assert_eq!(
+			hello_name(router.route("someone@mail.yahoo.com", &mut time_left).await),
+			Some("yahoo".into())
+		);
+		assert_eq!(
+			router.route("someone@notyahoo.com", &mut time_left).await,
+			None
+		);
+		assert_eq!(router.route("not an email", &mut time_left).await, None);
+	}
+
+	#[test]
+	fn test_matches_mx() {
+		let rule = RoutingRule {
+			mx_hosts: vec!["yahoodns.net".into()],
+			..Default::default()
+		};
+		assert!(rule.matches_mx(&["mta5.am0.yahoodns.net.".into()]));
+		assert!(!rule.matches_mx(&["gmail-smtp-in.l.google.com.".into()]));
+	}
+}
@@ -16,24 +16,38 @@
 
 //! Helper functions to send events to Sentry.
 //!
-//! This module also contains functions that check if the error's given by
-//! `check-if-email-exists` are known errors, in which case we don't log them
-//! to Sentry.
+//! The errors given by `check-if-email-exists` are only sent to Sentry if
+//! they are unknown to the [classifier](crate::classify).
 
 use super::sentry_util;
-use async_smtp::smtp::error::Error as AsyncSmtpError;
-use check_if_email_exists::{smtp::SmtpError, CheckEmailOutput};
-use sentry::protocol::{Event, Level, Value};
-use std::io::Error as IoError;
-use std::{collections::BTreeMap, env};
+use crate::classify::ErrorCategory;
+use crate::config::SentryConfig;
+use crate::redact::Redactor;
+use check_if_email_exists::CheckEmailOutput;
+use once_cell::sync::OnceCell;
+use sentry::{
+	protocol::{Event, Level, Value},
+	ClientOptions,
+};
+use std::{collections::BTreeMap, sync::Arc};
 
 pub const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-/// Setup Sentry.
-pub fn setup_sentry() -> sentry::ClientInitGuard {
-	// Use an empty string if we don't have any env variable for sentry. Sentry
-	// will just silently ignore.
-	let sentry = sentry::init(env::var("RCH_SENTRY_DSN").unwrap_or_else(|_| "".into()));
+/// The Sentry configuration, set once by [`setup_sentry`].
+static CONFIG: OnceCell<SentryConfig> = OnceCell::new();
+
+/// Setup Sentry, redacting all the events with `redactor`.
+pub fn setup_sentry(config: &SentryConfig, redactor: Arc<Redactor>) -> sentry::ClientInitGuard {
+	let _ = CONFIG.set(config.clone());
+	// Use an empty string if we don't have any DSN for sentry. Sentry will
+	// just silently ignore.
+	let sentry = sentry::init((
+		config.dsn.clone().unwrap_or_default(),
+		ClientOptions {
+			before_send: Some(Arc::new(move |event| redact_event(&redactor, event))),
+			..Default::default()
+		},
+	));
 	if sentry.is_enabled() {
 		log::info!(target: "reacher", "Sentry is successfully set up.")
 	}
@@ -41,23 +55,24 @@ pub fn setup_sentry() -> sentry::ClientInitGuard {
 	sentry
 }
 
-/// If BACKEND_NAME environment variable is set, add it to the sentry `extra`
+/// If the backend name is configured, add it to the sentry `extra`
 /// properties.
-/// For backwards compatibility, we also support HEROKU_APP_NAME env variable.
 fn add_backend_name(mut extra: BTreeMap<String, Value>) -> BTreeMap<String, Value> {
-	if let Ok(n) = env::var("BACKEND_NAME") {
-		extra.insert("BACKEND_NAME".into(), n.into());
-	} else if let Ok(n) = env::var("HEROKU_APP_NAME") {
-		extra.insert("BACKEND_NAME".into(), n.into());
+	if let Some(n) = CONFIG.get().and_then(|c| c.backend_name.as_ref()) {
+		extra.insert("BACKEND_NAME".into(), n.clone().into());
 	}
 
 	extra
 }
 
-/// Helper function to send an Info event to Sentry. We use these events for
-/// analytics purposes (I know, Sentry shouldn't be used for that...).
-/// TODO https://github.com/reacherhq/backend/issues/207
+/// Helper function to send an Info event to Sentry. We used these events for
+/// analytics purposes before the Prometheus metrics existed, they are now
+/// only sent if `RCH_SENTRY_METRICS=1`.
 pub fn metrics(message: String, duration: u128, domain: &str) {
+	if !CONFIG.get().is_some_and(|c| c.metrics) {
+		return;
+	}
+
 	log::info!(target: "reacher", "Sending info to Sentry: {}", message);
 
 	let mut extra = BTreeMap::new();
@@ -75,15 +90,18 @@ pub fn metrics(message: String, duration: u128, domain: &str) {
 	});
 }
 
-/// Helper function to send an Error event to Sentry. We redact all sensitive
-/// info before sending to Sentry, but removing all instances of `username`.
-pub fn error(message: String, result: Option<&str>, username: &str) {
-	let redacted_message = redact(message.as_str(), username);
+/// Helper function to send an Error event to Sentry, about `email`. Its
+/// parts are redacted too, see [`Redactor::text_about`].
+pub fn error(message: String, result: Option<&str>, email: &str, redactor: &Redactor) {
+	let redacted_message = redactor.text_about(&message, email);
 	log::debug!(target: "reacher", "Sending error to Sentry: {}", redacted_message);
 
 	let mut extra = BTreeMap::new();
 	if let Some(result) = result {
-		extra.insert("CheckEmailOutput".into(), redact(result, username).into());
+		extra.insert(
+			"CheckEmailOutput".into(),
+			redactor.text_about(result, email).into(),
+		);
 	}
 
 	extra = add_backend_name(extra);
@@ -97,96 +115,51 @@ pub fn error(message: String, result: Option<&str>, username: &str) {
 	});
 }
 
-/// Function to replace all usernames from email, and replace them with
-/// `***@domain.com` for privacy reasons.
-fn redact(input: &str, username: &str) -> String {
-	input.replace(username, "***")
-}
-
-/// Check if the message contains known SMTP IO errors.
-fn has_smtp_io_errors(error: &IoError) -> bool {
-	// code: 104, kind: ConnectionReset, message: "Connection reset by peer",
-	error.raw_os_error() == Some(104) ||
-	// kind: Other, error: "incomplete",
-	error.to_string() == "incomplete"
-}
-
-/// Check if the message contains known SMTP Transient errors.
-fn has_smtp_transient_errors(message: &[String]) -> bool {
-	let first_line = message[0].to_lowercase();
-
-	// 4.3.2 Please try again later
-	first_line.contains("try again") ||
-	// Temporary local problem - please try later
-	first_line.contains("try later")
-}
-
-/// Checks if the output from `check-if-email-exists` has a known error, in
-/// which case we don't log to Sentry to avoid spamming it.
-pub fn log_unknown_errors(result: &CheckEmailOutput) {
-	match (&result.misc, &result.mx, &result.smtp) {
-		(Err(error), _, _) => {
-			// We log misc errors.
-			sentry_util::error(
-				format!("{:?}", error),
-				Some(format!("{:#?}", result).as_ref()),
-				result.syntax.username.as_str(),
-			);
-		}
-		(_, Err(error), _) => {
-			// We log mx errors.
-			sentry_util::error(
-				format!("{:?}", error),
-				Some(format!("{:#?}", result).as_ref()),
-				result.syntax.username.as_str(),
-			);
-		}
-		(_, _, Err(SmtpError::SmtpError(AsyncSmtpError::Transient(response))))
-			if has_smtp_transient_errors(&response.message) =>
-		{
-			log::debug!(target: "reacher", "Transient error: {}", response.message[0]);
-		}
-		(_, _, Err(SmtpError::SmtpError(AsyncSmtpError::Io(err)))) if has_smtp_io_errors(err) => {
-			log::debug!(target: "reacher", "Io error: {}", err);
+/// Redact all the events sent to Sentry, including the ones we don't create
+/// ourselves, like panics.
+fn redact_event(redactor: &Redactor, mut event: Event<'static>) -> Option<Event<'static>> {
+	if let Some(message) = &mut event.message {
+		*message = redactor.text(message).into_owned();
+	}
+	for value in event.extra.values_mut() {
+		if let Value::String(s) = value {
+			*s = redactor.text(s).into_owned();
 		}
-		(_, _, Err(error)) => {
-			// If it's a SMTP error we didn't catch above, we log to
-			// Sentry, to be able to debug them better. We don't want to
-			// spam Sentry and log all instances of the error, hence the
-			// `count` check.
-			sentry_util::error(
-				format!("{:?}", error),
-				Some(format!("{:#?}", result).as_ref()),
-				result.syntax.username.as_str(),
-			);
+	}
+	for exception in event.exception.values.iter_mut() {
+		if let Some(value) = &mut exception.value {
+			*value = redactor.text(value).into_owned();
 		}
-		// If everything is ok, we just return the result.
-		(Ok(_), Ok(_), Ok(_)) => {}
 	}
-}
 
-#[cfg(test)]
-mod tests {
-	use super::redact;
+	Some(event)
+}
 
-	#[test]
-	fn test_redact() {
-		assert_eq!("***@gmail.com", redact("someone@gmail.com", "someone"));
-		assert_eq!(
-			"my email is ***@gmail.com.",
-			redact("my email is someone@gmail.com.", "someone")
-		);
-		assert_eq!(
-			"my email is ***@gmail.com., I repeat, my email is ***@gmail.com.",
-			redact(
-				"my email is someone@gmail.com., I repeat, my email is someone@gmail.com.",
-				"someone"
-			)
-		);
-		assert_eq!(
-			"*** @ gmail . com",
-			redact("someone @ gmail . com", "someone")
-		);
-		assert_eq!("*** is here.", redact("someone is here.", "someone"));
+/// Report the error of the output from `check-if-email-exists` to Sentry,
+/// unless the classifier knows it, to avoid spamming Sentry. `category` is the
+/// one given by the classifier.
+pub fn log_unknown_errors(
+	result: &CheckEmailOutput,
+	category: Option<ErrorCategory>,
+	redactor: &Redactor,
+) {
+	let error = match (&result.misc, &result.mx, &result.smtp) {
+		(Err(error), _, _) => format!("{:?}", error),
+		(_, Err(error), _) => format!("{:?}", error),
+		(_, _, Err(error)) => format!("{:?}", error),
+		// If everything is ok, there's nothing to report.
+		(Ok(_), Ok(_), Ok(_)) => return,
+	};
+
+	match category {
+		Some(ErrorCategory::Unknown) | None => sentry_util::error(
+			error,
+			Some(format!("{:#?}", result).as_ref()),
+			result.input.as_str(),
+			redactor,
+		),
+		Some(category) => {
+			log::debug!(target: "reacher", "Known error [category={}]: {}", category.as_str(), error);
+		}
 	}
 }
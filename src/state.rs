@@ -0,0 +1,57 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! State of the process built from the [`Config`] at startup, like the
+//! error classifier, the redactor, the keyring or the proxy pools. It's
+//! passed as an `Arc<AppState>` to the routes, the bulk tasks and the
+//! subcommands which need it.
+
+use crate::classify::ErrorClassifier;
+use crate::config::Config;
+use crate::encryption::Keyring;
+use crate::proxy::ProxyPools;
+use crate::redact::Redactor;
+use crate::routing::Router;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct AppState {
+	/// Classifies the verification errors, with the rules of the config.
+	pub classifier: ErrorClassifier,
+	/// Redacts the logs and Sentry events. Also owned by the log writer and
+	/// the Sentry client.
+	pub redactor: Arc<Redactor>,
+	/// Protects the emails of the stored results.
+	pub keyring: Keyring,
+	/// Proxies of the verifications without their own.
+	pub proxies: ProxyPools,
+	/// Per-domain overrides of the verification options.
+	pub router: Router,
+}
+
+impl AppState {
+	pub fn new(config: &Config) -> Self {
+		AppState {
+			classifier: ErrorClassifier::new(&config.error_rules),
+			redactor: Arc::new(Redactor::new(&config.redaction)),
+			keyring: Keyring::from_config(&config.encryption)
+				.expect("Encryption is validated on load. qed."),
+			proxies: ProxyPools::from_config(&config.proxies)
+				.expect("Proxies are validated on load. qed."),
+			router: Router::new(&config.routing),
+		}
+	}
+}
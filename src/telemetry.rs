@@ -0,0 +1,437 @@
+// Reacher - Email Verification
+// Copyright (C) 2018-2022 Reacher
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Logs and OpenTelemetry tracing.
+//!
+//! Logs and spans go through the `tracing` crate. Spans are exported via OTLP
+//! if an endpoint is configured. The trace context is propagated with the W3C
+//! `traceparent` header on HTTP requests, and inside the sqlxmq payload for
+//! bulk tasks, so that one email can be followed from the `POST /v0/bulk`
+//! request to its verification.
+
+use crate::config::Config;
+use crate::redact::Redactor;
+use crate::routes::route_label;
+use opentelemetry::{
+	global,
+	propagation::TextMapPropagator,
+	sdk::{propagation::TraceContextPropagator, trace, Resource},
+	trace::TraceError,
+	Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::types::chrono::Utc;
+use std::{
+	collections::HashMap,
+	error::Error,
+	fmt,
+	io::{self, IsTerminal},
+	sync::Arc,
+};
+use tracing::{
+	field::{Field, Visit},
+	Event, Level, Span, Subscriber,
+};
+use tracing_log::NormalizeEvent;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::field::{RecordFields, VisitOutput};
+use tracing_subscriber::{
+	filter::{EnvFilter, Targets},
+	fmt::{
+		format::{DefaultVisitor, JsonFields, Writer},
+		FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter,
+	},
+	prelude::*,
+	registry::LookupSpan,
+	Layer,
+};
+
+/// Format of the logs.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+	/// Human-readable lines, the default.
+	Text,
+	/// One JSON object per line, with the fields of the enclosing spans
+	/// (`request_id`, `job_id`, `task_uuid`, `domain`...) flattened next to
+	/// the fields of the event.
+	Json,
+}
+
+/// Flushes the pending spans when dropped.
+pub struct TelemetryGuard {
+	tracing: bool,
+}
+
+impl Drop for TelemetryGuard {
+	fn drop(&mut self) {
+		if self.tracing {
+			global::shutdown_tracer_provider();
+		}
+	}
+}
+
+/// Create the OTLP tracer, if an endpoint is configured (e.g.
+/// `http://localhost:4317` for a local collector).
+fn otlp_tracer(endpoint: Option<&str>) -> Result<Option<trace::Tracer>, TraceError> {
+	let endpoint = match endpoint {
+		Some(endpoint) => endpoint,
+		None => return Ok(None),
+	};
+
+	opentelemetry_otlp::new_pipeline()
+		.tracing()
+		.with_exporter(
+			opentelemetry_otlp::new_exporter()
+				.tonic()
+				.with_endpoint(endpoint),
+		)
+		.with_trace_config(trace::config().with_resource(Resource::new(vec![
+			KeyValue::new("service.name", "reacher_backend"),
+			KeyValue::new("service.version", crate::sentry_util::CARGO_PKG_VERSION),
+		])))
+		.install_batch(opentelemetry::runtime::Tokio)
+		.map(Some)
+}
+
+/// Setup the logs, filtered by `RUST_LOG`, formatted according to the config
+/// and redacted, and the OTLP trace exporter. Records of the `log` crate are
+/// forwarded too. Must be called from within the tokio runtime.
+pub fn setup_telemetry(
+	config: &Config,
+	redactor: Arc<Redactor>,
+) -> Result<TelemetryGuard, Box<dyn Error + Send + Sync>> {
+	global::set_text_map_propagator(TraceContextPropagator::new());
+
+	// Logs go to stderr, once redacted.
+	let fmt_layer = fmt_layer(config.log_format, redactor, io::stderr);
+	let tracer = otlp_tracer(config.otlp_endpoint.as_deref())?;
+	let tracing = tracer.is_some();
+
+	tracing_subscriber::registry()
+		.with(fmt_layer.with_filter(EnvFilter::from_default_env()))
+		// Only export our own spans, the ones of our dependencies (e.g. the
+		// OTLP exporter itself) are noise.
+		.with(tracer.map(|tracer| {
+			tracing_opentelemetry::layer()
+				.with_tracer(tracer)
+				.with_filter(Targets::new().with_target("reacher_backend", Level::DEBUG))
+		}))
+		.try_init()?;
+
+	if tracing {
+		log::info!(target: "reacher", "Exporting traces via OTLP.");
+	}
+
+	Ok(TelemetryGuard { tracing })
+}
+
+/// The layer writing the logs in the given format, with the values of their
+/// fields redacted before they are formatted: once formatted, e.g. as JSON,
+/// the quotes of the values are escaped, and the credentials they contain
+/// would not be recognized anymore.
+fn fmt_layer<S, W>(
+	format: LogFormat,
+	redactor: Arc<Redactor>,
+	writer: W,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+	W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+	let fmt_layer = tracing_subscriber::fmt::layer().with_writer(writer);
+	match format {
+		LogFormat::Text => fmt_layer
+			.with_ansi(io::stderr().is_terminal())
+			.fmt_fields(TextFields(redactor))
+			.boxed(),
+		LogFormat::Json => fmt_layer
+			.with_ansi(false)
+			.fmt_fields(JsonFields::new())
+			.event_format(JsonFormat(redactor))
+			.boxed(),
+	}
+}
+
+/// Formats the fields like `tracing_subscriber` does by default, once
+/// redacted.
+struct TextFields(Arc<Redactor>);
+
+impl<'w> FormatFields<'w> for TextFields {
+	fn format_fields<R: RecordFields>(&self, writer: Writer<'w>, fields: R) -> fmt::Result {
+		let mut visitor = TextVisitor {
+			redactor: &self.0,
+			inner: DefaultVisitor::new(writer, true),
+		};
+		fields.record(&mut visitor);
+
+		visitor.inner.finish()
+	}
+}
+
+/// Redacts the values of the fields before handing them to the
+/// `tracing_subscriber` default visitor.
+struct TextVisitor<'a, 'w> {
+	redactor: &'a Redactor,
+	inner: DefaultVisitor<'w>,
+}
+
+impl Visit for TextVisitor<'_, '_> {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		let value = format!("{:?}", value);
+		self.inner
+			.record_debug(field, &format_args!("{}", self.redactor.text(&value)));
+	}
+
+	fn record_str(&mut self, field: &Field, value: &str) {
+		self.inner.record_str(field, &self.redactor.text(value));
+	}
+
+	fn record_i64(&mut self, field: &Field, value: i64) {
+		self.inner.record_i64(field, value);
+	}
+
+	fn record_u64(&mut self, field: &Field, value: u64) {
+		self.inner.record_u64(field, value);
+	}
+
+	fn record_bool(&mut self, field: &Field, value: bool) {
+		self.inner.record_bool(field, value);
+	}
+}
+
+/// See [`LogFormat::Json`].
+struct JsonFormat(Arc<Redactor>);
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+{
+	fn format_event(
+		&self,
+		ctx: &FmtContext<'_, S, JsonFields>,
+		mut writer: Writer<'_>,
+		event: &Event<'_>,
+	) -> fmt::Result {
+		// Records of the `log` crate have their own metadata.
+		let normalized = event.normalized_metadata();
+		let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+		let mut fields = Map::new();
+		fields.insert("timestamp".into(), Utc::now().to_rfc3339().into());
+		fields.insert("level".into(), metadata.level().to_string().into());
+		fields.insert("target".into(), metadata.target().into());
+
+		// Outermost span first, so that inner spans override its fields.
+		if let Some(scope) = ctx.event_scope() {
+			for span in scope.from_root() {
+				let extensions = span.extensions();
+				if let Some(span_fields) = extensions.get::<FormattedFields<JsonFields>>() {
+					if let Ok(Value::Object(span_fields)) = serde_json::from_str(span_fields) {
+						fields.extend(span_fields.into_iter().map(|(k, v)| match v {
+							Value::String(v) => (k, self.0.text(&v).into_owned().into()),
+							v => (k, v),
+						}));
+					}
+				}
+			}
+		}
+
+		event.record(&mut JsonVisitor {
+			fields: &mut fields,
+			redactor: &self.0,
+		});
+
+		writeln!(writer, "{}", Value::Object(fields))
+	}
+}
+
+/// Collects the fields of an event into a JSON object, once redacted.
+struct JsonVisitor<'a> {
+	fields: &'a mut Map<String, Value>,
+	redactor: &'a Redactor,
+}
+
+impl JsonVisitor<'_> {
+	fn insert(&mut self, field: &Field, value: Value) {
+		// The metadata of `log` records, already handled above.
+		if !field.name().starts_with("log.") {
+			self.fields.insert(field.name().into(), value);
+		}
+	}
+}
+
+impl Visit for JsonVisitor<'_> {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		let value = format!("{:?}", value);
+		self.insert(field, self.redactor.text(&value).into_owned().into());
+	}
+
+	fn record_str(&mut self, field: &Field, value: &str) {
+		self.insert(field, self.redactor.text(value).into_owned().into());
+	}
+
+	fn record_i64(&mut self, field: &Field, value: i64) {
+		self.insert(field, value.into());
+	}
+
+	fn record_u64(&mut self, field: &Field, value: u64) {
+		self.insert(field, value.into());
+	}
+
+	fn record_bool(&mut self, field: &Field, value: bool) {
+		self.insert(field, value.into());
+	}
+}
+
+/// Log a served HTTP request. Meant to be used with `warp::log::custom`.
+pub fn log_request(info: warp::log::Info) {
+	tracing::info!(
+		target: "reacher",
+		status = info.status().as_u16(),
+		duration_ms = info.elapsed().as_millis() as u64,
+		"{} {} {}",
+		info.method(),
+		route_label(info.path()),
+		info.status(),
+	);
+}
+
+/// Create the span of an incoming HTTP request, child of the trace context
+/// given in its headers, if any. Meant to be used with `warp::trace`.
+pub fn http_request_span(info: warp::trace::Info) -> Span {
+	let span = tracing::info_span!(
+		"http_request",
+		method = %info.method(),
+		route = %route_label(info.path()),
+		request_id = tracing::field::Empty,
+	);
+
+	let carrier = info
+		.request_headers()
+		.iter()
+		.filter_map(|(k, v)| Some((k.as_str().to_string(), v.to_str().ok()?.to_string())))
+		.collect();
+	span.set_parent(extract_context(&carrier));
+
+	span
+}
+
+/// Serialize the trace context of a span, to be sent to another process.
+pub fn inject_context(span: &Span) -> HashMap<String, String> {
+	let mut carrier = HashMap::new();
+	TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+
+	carrier
+}
+
+/// Deserialize a trace context serialized with [`inject_context`], or taken
+/// from HTTP headers.
+pub fn extract_context(carrier: &HashMap<String, String>) -> Context {
+	TraceContextPropagator::new().extract(carrier)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::RedactionConfig;
+	use crate::redact::EmailRedaction;
+	use check_if_email_exists::CheckEmailInputProxy;
+	use opentelemetry::trace::TraceContextExt;
+	use std::sync::Mutex;
+
+	/// Log lines written by [`fmt_layer`].
+	#[derive(Clone, Default)]
+	struct Lines(Arc<Mutex<Vec<u8>>>);
+
+	impl io::Write for Lines {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn log(format: LogFormat) -> String {
+		let redactor = Arc::new(Redactor::new(&RedactionConfig {
+			emails: EmailRedaction::Mask,
+			salt: None,
+			proxy_credentials: true,
+		}));
+		let lines = Lines::default();
+		let writer = lines.clone();
+		let subscriber =
+			tracing_subscriber::registry()
+				.with(fmt_layer(format, redactor, move || writer.clone()));
+
+		let input = CheckEmailInputProxy {
+			host: "my-proxy.io".into(),
+			port: 1080,
+			username: Some("me".into()),
+			password: Some(r#"s3cr"et"#.into()),
+		};
+		tracing::subscriber::with_default(subscriber, || {
+			let _span = tracing::info_span!("task", to_email = "someone@gmail.com").entered();
+			tracing::error!(input = ?input, "[input={:?}]", input);
+		});
+
+		let lines = lines.0.lock().unwrap();
+		String::from_utf8_lossy(&lines).into_owned()
+	}
+
+	#[test]
+	fn test_redact_json_logs() {
+		let line = log(LogFormat::Json);
+		let fields: Map<String, Value> = serde_json::from_str(&line).unwrap();
+		assert_eq!(fields["to_email"], "***@gmail.com");
+		let input = r#"CheckEmailInputProxy { host: "my-proxy.io", port: 1080, username: Some("***"), password: Some("***") }"#;
+		assert_eq!(fields["input"], input);
+		assert_eq!(fields["message"], format!("[input={}]", input));
+		assert!(!line.contains("s3cr"));
+		assert!(!line.contains("someone"));
+	}
+
+	#[test]
+	fn test_redact_text_logs() {
+		let line = log(LogFormat::Text);
+		assert!(line.contains("***@gmail.com"));
+		assert!(!line.contains("s3cr"));
+		assert!(!line.contains("someone"));
+	}
+
+	#[test]
+	fn test_extract_context() {
+		let mut carrier = HashMap::new();
+		carrier.insert(
+			"traceparent".to_string(),
+			"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".to_string(),
+		);
+
+		let cx = extract_context(&carrier);
+		assert_eq!(
+			cx.span().span_context().trace_id().to_string(),
+			"0af7651916cd43dd8448eb211c80319c"
+		);
+
+		// Without a subscriber, spans have no context to inject.
+		assert!(inject_context(&Span::none()).is_empty());
+	}
+}
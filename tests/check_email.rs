@@ -14,8 +14,10 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use reacher_backend::config::Config;
 use reacher_backend::routes::{check_email::post::EndpointRequest, create_routes};
-use serde_json;
+use reacher_backend::state::AppState;
+use std::sync::Arc;
 use warp::http::StatusCode;
 use warp::test::request;
 
@@ -24,11 +26,13 @@ const FOO_BAR_BAZ_RESPONSE: &str = r#"{"input":"foo@bar.baz","is_reachable":"inv
 
 #[tokio::test]
 async fn test_input_foo_bar() {
+	let config = Arc::new(Config::default());
+	let state = Arc::new(AppState::new(&config));
 	let resp = request()
 		.path("/v0/check_email")
 		.method("POST")
 		.json(&serde_json::from_str::<EndpointRequest>(r#"{"to_email": "foo@bar"}"#).unwrap())
-		.reply(&create_routes(None))
+		.reply(&create_routes(config, state, None, None))
 		.await;
 
 	assert_eq!(resp.status(), StatusCode::OK);
@@ -37,11 +41,13 @@ async fn test_input_foo_bar() {
 
 #[tokio::test]
 async fn test_input_foo_bar_baz() {
+	let config = Arc::new(Config::default());
+	let state = Arc::new(AppState::new(&config));
 	let resp = request()
 		.path("/v0/check_email")
 		.method("POST")
 		.json(&serde_json::from_str::<EndpointRequest>(r#"{"to_email": "foo@bar.baz"}"#).unwrap())
-		.reply(&create_routes(None))
+		.reply(&create_routes(config, state, None, None))
 		.await;
 
 	assert_eq!(resp.status(), StatusCode::OK);